@@ -1,6 +1,10 @@
 use bevy::prelude::*;
 use bevy_steam_p2p::{
+    lobby_settings::LobbyPrivacy,
     networked_messages::{message::Networked, register::NetworkedMessages},
+    networked_movable::NetworkedMovable,
+    networked_transform::NetworkedTransform,
+    prefab::NetworkPrefabs,
     FilePath, NetworkData,
 };
 use bevy_steam_p2p::{SteamP2PClient, SteamP2PPlugin};
@@ -13,13 +17,32 @@ struct TestMessage {
 }
 
 fn main() {
-    App::new()
-        .add_plugins(SteamP2PPlugin)
+    let mut app = App::new();
+    app.add_plugins(SteamP2PPlugin)
         .add_plugins(DefaultPlugins)
         .add_systems(Startup, startup)
         .add_systems(Update, (update, listener))
-        .add_networked_message::<TestMessage>()
-        .run();
+        .add_networked_message::<TestMessage>();
+
+    let mesh = app
+        .world_mut()
+        .resource_mut::<Assets<Mesh>>()
+        .add(Cuboid::new(1.0, 1.0, 1.0));
+    let material = app
+        .world_mut()
+        .resource_mut::<Assets<StandardMaterial>>()
+        .add(Color::srgb_u8(124, 144, 255));
+    app.register_network_prefab("InstantiationExample", move |commands, _data| {
+        commands
+            .spawn((
+                Mesh3d(mesh.clone()),
+                MeshMaterial3d(material.clone()),
+                NetworkedTransform::default(),
+                NetworkedMovable { speed: 10. },
+            ))
+            .id()
+    });
+    app.run();
 }
 
 fn startup(mut commands: Commands) {
@@ -40,7 +63,7 @@ fn update(
     mut test_w: MessageWriter<Networked<TestMessage>>,
 ) {
     if keys.just_pressed(KeyCode::KeyC) {
-        client.create_lobby(8);
+        client.create_lobby(LobbyPrivacy::Public, 8);
     }
     if keys.just_pressed(KeyCode::KeyT) {
         client
@@ -0,0 +1,344 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use steamworks::networking_types::SendFlags;
+
+use crate::{
+    client::SteamP2PClient, networked_transform::CompactTransform, NetworkData, NetworkId, SteamId,
+};
+
+pub struct BandwidthPlugin;
+
+impl Plugin for BandwidthPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BandwidthBudget>()
+            .init_resource::<StateUpdateQueue>()
+            .add_systems(FixedPostUpdate, flush_state_updates);
+    }
+}
+
+//Outgoing budget applied to each peer separately, None sends everything immediately
+#[derive(Resource, Default)]
+pub struct BandwidthBudget {
+    pub bytes_per_second: Option<u32>,
+}
+
+impl BandwidthBudget {
+    pub fn new(bytes_per_second: u32) -> Self {
+        BandwidthBudget {
+            bytes_per_second: Some(bytes_per_second),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        BandwidthBudget {
+            bytes_per_second: None,
+        }
+    }
+}
+
+//How fast an entity's unsent state climbs the send order, relative to others
+#[derive(Component, Clone, Copy, Debug)]
+pub struct NetworkPriority(pub f32);
+
+impl Default for NetworkPriority {
+    fn default() -> Self {
+        NetworkPriority(1.)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum StateSlot {
+    Transform,
+    Component(u8), //Index in the ReplicationRegister
+}
+
+struct PendingState {
+    data: NetworkData,
+    flags: SendFlags,
+    priority: f32,
+    accumulated: f32,
+}
+
+#[derive(Default)]
+struct PeerState {
+    budget: f32,
+    pending: HashMap<(NetworkId, StateSlot), PendingState>,
+}
+
+impl PeerState {
+    fn merge(&mut self, update: &QueuedState) {
+        let (data, flags, accumulated) = match self.pending.remove(&update.key) {
+            //A reliable update stays reliable when newer ones are merged into it
+            Some(pending) => (
+                coalesce(pending.data, update.data.clone()),
+                if pending.flags.contains(SendFlags::RELIABLE) {
+                    pending.flags
+                } else {
+                    update.flags
+                },
+                pending.accumulated,
+            ),
+            None => (update.data.clone(), update.flags, 0.),
+        };
+        self.pending.insert(
+            update.key,
+            PendingState {
+                data,
+                flags,
+                priority: update.priority,
+                accumulated,
+            },
+        );
+    }
+
+    //Picks this tick's sends, highest accumulated priority first, whatever doesn't fit waits for the next tick
+    fn schedule(&mut self, bytes_per_second: Option<u32>, delta: f32) -> Vec<(Vec<u8>, SendFlags)> {
+        for pending in self.pending.values_mut() {
+            pending.accumulated += pending.priority;
+        }
+        let mut order: Vec<(NetworkId, StateSlot)> = self.pending.keys().copied().collect();
+        order.sort_by(|a, b| {
+            self.pending[b]
+                .accumulated
+                .total_cmp(&self.pending[a].accumulated)
+        });
+        if let Some(bytes_per_second) = bytes_per_second {
+            let bytes_per_second = bytes_per_second as f32;
+            self.budget = (self.budget + bytes_per_second * delta).min(bytes_per_second);
+        }
+
+        let mut sends = Vec::new();
+        for key in order {
+            //The budget may go negative so large updates still get through, the debt is paid off on later ticks
+            if bytes_per_second.is_some() && self.budget <= 0. {
+                break;
+            }
+            let pending = self.pending.remove(&key).unwrap();
+            let Ok(serialized) = rmp_serde::to_vec(&pending.data) else {
+                continue;
+            };
+            self.budget -= serialized.len() as f32;
+            sends.push((serialized, pending.flags));
+        }
+        sends
+    }
+}
+
+struct QueuedState {
+    key: (NetworkId, StateSlot),
+    data: NetworkData,
+    flags: SendFlags,
+    priority: f32,
+}
+
+//State updates waiting to be sent, newer updates are merged into older ones for the same slot
+#[derive(Resource, Default)]
+pub(crate) struct StateUpdateQueue {
+    queued: Vec<QueuedState>,
+    peers: HashMap<SteamId, PeerState>,
+}
+
+impl StateUpdateQueue {
+    pub fn push(
+        &mut self,
+        network_id: NetworkId,
+        slot: StateSlot,
+        data: NetworkData,
+        flags: SendFlags,
+        priority: Option<&NetworkPriority>,
+    ) {
+        self.queued.push(QueuedState {
+            key: (network_id, slot),
+            data,
+            flags,
+            priority: priority.copied().unwrap_or_default().0.max(0.01),
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.queued.clear();
+        self.peers.clear();
+    }
+}
+
+//Transform updates only carry the fields that changed, keep the older ones the newer update lacks
+fn coalesce(older: NetworkData, newer: NetworkData) -> NetworkData {
+    match (older, newer) {
+        (
+            NetworkData::TransformUpdate(_, old_position, old_rotation, old_scale),
+            NetworkData::TransformUpdate(network_identity, position, rotation, scale),
+        ) => NetworkData::TransformUpdate(
+            network_identity,
+            position.or(old_position),
+            rotation.or(old_rotation),
+            scale.or(old_scale),
+        ),
+        (
+            NetworkData::CompactTransformUpdate(_, old),
+            NetworkData::CompactTransformUpdate(network_id, new),
+        ) if old.bounds_id == new.bounds_id => NetworkData::CompactTransformUpdate(
+            network_id,
+            CompactTransform {
+                position: new.position.or(old.position),
+                rotation: new.rotation.or(old.rotation),
+                scale: new.scale.or(old.scale),
+                bounds_id: new.bounds_id,
+            },
+        ),
+        (_, newer) => newer,
+    }
+}
+
+fn flush_state_updates(
+    client: Res<SteamP2PClient>,
+    budget: Res<BandwidthBudget>,
+    mut queue: ResMut<StateUpdateQueue>,
+    time: Res<Time>,
+) {
+    let Ok(members) = client.get_lobby_members() else {
+        queue.clear();
+        return;
+    };
+    let peers: Vec<SteamId> = members
+        .into_iter()
+        .filter(|member| *member != client.id)
+        .collect();
+    let queued = std::mem::take(&mut queue.queued);
+    queue.peers.retain(|peer, _| peers.contains(peer));
+
+    for peer in peers {
+        let state = queue.peers.entry(peer).or_default();
+        //Nothing is spent on entities the peer doesn't have
+        state
+            .pending
+            .retain(|(network_id, _), _| client.relevancy.is_visible(*network_id, peer));
+        for update in queued
+            .iter()
+            .filter(|update| client.relevancy.is_visible(update.key.0, peer))
+        {
+            state.merge(update);
+        }
+        for (serialized, flags) in state.schedule(budget.bytes_per_second, time.delta_secs()) {
+            if let Err(err) = client.send_serialized(&serialized, peer, flags) {
+                println!("Couldn't send state update: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FilePath, NetworkIdentity};
+
+    fn update(index: u32, priority: f32) -> QueuedState {
+        let network_id = NetworkId {
+            owner: SteamId::from_raw(1),
+            index,
+        };
+        QueuedState {
+            key: (network_id, StateSlot::Component(0)),
+            data: NetworkData::ComponentUpdate(network_id, 0, vec![0; 16]),
+            flags: SendFlags::UNRELIABLE,
+            priority,
+        }
+    }
+
+    //A budget that lets exactly one of the test updates through per one second tick
+    fn one_update_per_tick() -> Option<u32> {
+        Some(rmp_serde::to_vec(&update(0, 1.).data).unwrap().len() as u32)
+    }
+
+    #[test]
+    fn unsent_updates_carry_over_to_next_tick() {
+        let mut state = PeerState::default();
+        state.merge(&update(0, 1.));
+        state.merge(&update(1, 1.));
+        assert_eq!(state.schedule(one_update_per_tick(), 1.).len(), 1);
+        assert_eq!(state.pending.len(), 1);
+        //Nothing new was queued, the leftover goes out now
+        assert_eq!(state.schedule(one_update_per_tick(), 1.).len(), 1);
+        assert!(state.pending.is_empty());
+        assert!(state.schedule(one_update_per_tick(), 1.).is_empty());
+    }
+
+    #[test]
+    fn low_priority_updates_are_not_starved() {
+        let mut state = PeerState::default();
+        let mut low_sent_at = None;
+        for tick in 0..20 {
+            //Both change every tick, the high priority one would always win on priority alone
+            state.merge(&update(0, 10.));
+            state.merge(&update(1, 1.));
+            let sends = state.schedule(one_update_per_tick(), 1.);
+            assert_eq!(sends.len(), 1);
+            if !state.pending.contains_key(&update(1, 1.).key) {
+                low_sent_at = Some(tick);
+                break;
+            }
+            assert!(!state.pending.contains_key(&update(0, 10.).key));
+        }
+        //Its priority has to add up to the high priority one's, ten ticks at most
+        assert!(low_sent_at.is_some_and(|tick| tick <= 10));
+    }
+
+    #[test]
+    fn unlimited_budget_sends_everything() {
+        let mut state = PeerState::default();
+        for index in 0..50 {
+            state.merge(&update(index, 1.));
+        }
+        assert_eq!(state.schedule(None, 1.).len(), 50);
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn coalescing_keeps_fields_missing_from_newer_update() {
+        let owner = SteamId::from_raw(1);
+        let network_id = NetworkId { owner, index: 0 };
+        let network_identity = NetworkIdentity {
+            id: network_id,
+            parent_id: None,
+            instantiation_path: FilePath::new("Test"),
+            owner,
+        };
+        let older = NetworkData::TransformUpdate(
+            network_identity.clone(),
+            Some(Vec3::X),
+            Some(Quat::from_rotation_y(1.)),
+            None,
+        );
+        let newer =
+            NetworkData::TransformUpdate(network_identity, Some(Vec3::Y), None, Some(Vec3::ONE));
+        let NetworkData::TransformUpdate(_, position, rotation, scale) = coalesce(older, newer)
+        else {
+            panic!("Coalesced into another kind of update");
+        };
+        assert_eq!(position, Some(Vec3::Y));
+        assert_eq!(rotation, Some(Quat::from_rotation_y(1.)));
+        assert_eq!(scale, Some(Vec3::ONE));
+
+        let older = NetworkData::CompactTransformUpdate(
+            network_id,
+            CompactTransform {
+                position: None,
+                rotation: Some(7),
+                scale: None,
+                bounds_id: 0,
+            },
+        );
+        let newer = NetworkData::CompactTransformUpdate(
+            network_id,
+            CompactTransform {
+                position: Some(123),
+                rotation: None,
+                scale: None,
+                bounds_id: 0,
+            },
+        );
+        let NetworkData::CompactTransformUpdate(_, compact) = coalesce(older, newer) else {
+            panic!("Coalesced into another kind of update");
+        };
+        assert_eq!(compact.position, Some(123));
+        assert_eq!(compact.rotation, Some(7));
+    }
+}
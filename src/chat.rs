@@ -0,0 +1,10 @@
+use bevy::prelude::*;
+
+use crate::SteamId;
+
+//Steam lobby chat, also delivered to the sender
+#[derive(Message, Clone, Debug)]
+pub struct LobbyChatReceived {
+    pub sender: SteamId,
+    pub text: String,
+}
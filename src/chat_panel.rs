@@ -0,0 +1,83 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts, EguiPrimaryContextPass};
+
+use crate::{chat::LobbyChatReceived, client::SteamP2PClient, LobbyLeft, SteamId};
+
+//Ready-made lobby chat window, needs bevy_egui's EguiPlugin
+pub struct LobbyChatPanelPlugin;
+
+impl Plugin for LobbyChatPanelPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LobbyChatPanel>()
+            .add_systems(Update, collect_chat)
+            .add_systems(EguiPrimaryContextPass, chat_panel_ui);
+    }
+}
+
+#[derive(Resource)]
+pub struct LobbyChatPanel {
+    pub open: bool,
+    pub max_history: usize,
+    history: Vec<(SteamId, String)>,
+    input: String,
+}
+
+impl Default for LobbyChatPanel {
+    fn default() -> Self {
+        Self {
+            open: true,
+            max_history: 100,
+            history: Vec::new(),
+            input: String::new(),
+        }
+    }
+}
+
+fn collect_chat(
+    mut evs_chat: MessageReader<LobbyChatReceived>,
+    mut evs_left: MessageReader<LobbyLeft>,
+    mut panel: ResMut<LobbyChatPanel>,
+) {
+    if evs_left.read().count() > 0 {
+        panel.history.clear();
+    }
+    for ev in evs_chat.read() {
+        panel.history.push((ev.sender, ev.text.clone()));
+    }
+    let overflow = panel.history.len().saturating_sub(panel.max_history);
+    panel.history.drain(..overflow);
+}
+
+fn chat_panel_ui(
+    mut contexts: EguiContexts,
+    client: Res<SteamP2PClient>,
+    mut panel: ResMut<LobbyChatPanel>,
+) -> Result {
+    if !panel.open || !client.is_in_lobby() {
+        return Ok(());
+    }
+    let friends = client.steam_client.friends();
+    let panel = &mut *panel;
+    egui::Window::new("Lobby chat").show(contexts.ctx_mut()?, |ui| {
+        egui::ScrollArea::vertical()
+            .max_height(200.)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for (sender, text) in &panel.history {
+                    ui.label(format!("{}: {}", friends.get_friend(*sender).name(), text));
+                }
+            });
+        ui.horizontal(|ui| {
+            let input = ui.text_edit_singleline(&mut panel.input);
+            let submitted = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if (ui.button("Send").clicked() || submitted) && !panel.input.trim().is_empty() {
+                if let Err(err) = client.send_lobby_chat(panel.input.trim()) {
+                    println!("Couldn't send chat message: {}", err);
+                }
+                panel.input.clear();
+                input.request_focus();
+            }
+        });
+    });
+    Ok(())
+}
@@ -1,7 +1,19 @@
+use std::{sync::Arc, time::Duration};
+
 use bevy::prelude::*;
 use bevy_steamworks::*;
+use serde::{de::DeserializeOwned, Serialize};
 
-use crate::*;
+use crate::{
+    invites::connect_string,
+    lobby::{
+        decode_data, encode_data, LobbyInfo, LobbyListFilters, LobbyService, SteamLobbyService,
+    },
+    lobby_settings::{LobbyPrivacy, LobbySettings, LobbySettingsUpdate},
+    relevancy::Relevancy,
+    session::{SessionRejectReason, SessionRejected, SharedSessionGate},
+    *,
+};
 
 #[derive(Resource)]
 pub struct SteamP2PClient {
@@ -9,8 +21,11 @@ pub struct SteamP2PClient {
     pub lobby_status: LobbyStatus,
     pub steam_client: bevy_steamworks::Client,
     pub(crate) steam_bevy_channel: SteamBevyChannel,
+    pub(crate) lobby_service: Arc<dyn LobbyService>,
     instantiation_id: u32,
-    queued_instantiations: Vec<InstantiationData>,
+    queued_instantiations: Vec<QueuedInstantiation>,
+    pub(crate) relevancy: Relevancy,
+    pub(crate) session_gate: SharedSessionGate,
 }
 
 impl SteamP2PClient {
@@ -23,46 +38,255 @@ impl SteamP2PClient {
             lobby_status: LobbyStatus::OutOfLobby,
             steam_client: steam_client.clone(),
             steam_bevy_channel: SteamBevyChannel { tx, rx },
+            lobby_service: Arc::new(SteamLobbyService(steam_client.clone())),
             instantiation_id: 0,
             queued_instantiations: Vec::new(),
+            relevancy: Relevancy::default(),
+            session_gate: SharedSessionGate::default(),
         }
     }
-    pub fn create_lobby(&self, max_players: u32) {
+    pub(crate) fn with_lobby_service(mut self, lobby_service: Arc<dyn LobbyService>) -> Self {
+        self.lobby_service = lobby_service;
+        self
+    }
+    pub fn create_lobby(&mut self, privacy: LobbyPrivacy, max_players: u32) {
         let tx: Sender<ChannelPacket> = self.steam_bevy_channel.tx.clone();
-        if self.lobby_status != LobbyStatus::OutOfLobby {
+        let session_gate = self.session_gate.clone();
+        if let Err(err) = self.can_enter_lobby() {
+            self.refuse_lobby_request(None, err);
             return;
         };
-        self.steam_client
-            .matchmaking()
-            .create_lobby(LobbyType::Public, max_players, move |res| {
-                if let Ok(lobby_id) = res {
+        self.lobby_status = LobbyStatus::Creating;
+        self.steam_client.matchmaking().create_lobby(
+            privacy.lobby_type(),
+            max_players,
+            move |res| match res {
+                Ok(lobby_id) => {
+                    //Members can open sessions before LobbyJoined is handled
+                    session_gate.write().expect("Session gate poisoned").lobby = Some(lobby_id);
                     match tx.send(ChannelPacket::LobbyJoined(lobby_id)) {
                         Ok(_) => {}
                         Err(_) => {}
                     }
+                    let settings = LobbySettings {
+                        privacy,
+                        joinable: true,
+                        max_members: max_players,
+                    };
+                    let _ = tx.send(ChannelPacket::LobbySettings(LobbySettingsUpdate::Created(
+                        settings,
+                    )));
                 }
-            });
+                Err(err) => {
+                    let _ = tx.send(ChannelPacket::LobbyJoinFailed(LobbyJoinFailed {
+                        lobby_id: None,
+                        error: format!("Couldn't create lobby: {}", err),
+                    }));
+                }
+            },
+        );
+    }
+    pub fn set_lobby_privacy(&self, privacy: LobbyPrivacy) -> Result<(), String> {
+        let lobby_id = self.get_owned_lobby_id()?;
+        if !self.lobby_service.set_lobby_privacy(lobby_id, privacy) {
+            return Err("Couldn't change the lobby privacy".to_string());
+        }
+        self.update_lobby_settings(LobbySettingsUpdate::Privacy(privacy));
+        Ok(())
+    }
+    pub fn set_lobby_joinable(&self, joinable: bool) -> Result<(), String> {
+        let lobby_id = self.get_owned_lobby_id()?;
+        if !self.lobby_service.set_lobby_joinable(lobby_id, joinable) {
+            return Err("Couldn't change whether the lobby is joinable".to_string());
+        }
+        self.update_lobby_settings(LobbySettingsUpdate::Joinable(joinable));
+        Ok(())
+    }
+    pub fn set_max_members(&self, max_members: u32) -> Result<(), String> {
+        let lobby_id = self.get_owned_lobby_id()?;
+        if !self.lobby_service.set_max_members(lobby_id, max_members) {
+            return Err("Couldn't change the lobby's max members".to_string());
+        }
+        self.update_lobby_settings(LobbySettingsUpdate::MaxMembers(max_members));
+        Ok(())
     }
-    pub fn join_lobby(&self, lobby_id: LobbyId) {
+    fn update_lobby_settings(&self, update: LobbySettingsUpdate) {
+        self.steam_bevy_channel
+            .tx
+            .send(ChannelPacket::LobbySettings(update))
+            .expect("Couldn't send lobby settings update");
+    }
+    pub fn join_lobby(&mut self, lobby_id: LobbyId) {
         let tx = self.steam_bevy_channel.tx.clone();
+        let session_gate = self.session_gate.clone();
+        if let Err(err) = self.can_enter_lobby() {
+            self.refuse_lobby_request(Some(lobby_id), err);
+            return;
+        };
+        self.lobby_status = LobbyStatus::Joining(lobby_id);
         self.steam_client
             .matchmaking()
-            .join_lobby(lobby_id, move |res| {
-                if let Ok(lobby_id) = res {
+            .join_lobby(lobby_id, move |res| match res {
+                Ok(lobby_id) => {
+                    session_gate.write().expect("Session gate poisoned").lobby = Some(lobby_id);
                     match tx.send(ChannelPacket::LobbyJoined(lobby_id)) {
                         Ok(_) => {}
                         Err(_) => {}
                     }
                 }
+                Err(_) => {
+                    let _ = tx.send(ChannelPacket::LobbyJoinFailed(LobbyJoinFailed {
+                        lobby_id: Some(lobby_id),
+                        error: format!("Couldn't join lobby {}", lobby_id.raw()),
+                    }));
+                }
             });
     }
+    //Leaving only waits for LobbyLeft to be handled, so we can already head somewhere else
+    fn can_enter_lobby(&self) -> Result<(), String> {
+        match self.lobby_status {
+            LobbyStatus::OutOfLobby | LobbyStatus::Leaving => Ok(()),
+            LobbyStatus::InLobby(_) => Err("Already in a lobby".to_string()),
+            LobbyStatus::Creating | LobbyStatus::Joining(_) => {
+                Err("Already creating or joining a lobby".to_string())
+            }
+        }
+    }
+    //The attempt in progress carries on, so this doesn't go through LobbyJoinFailed's status reset
+    fn refuse_lobby_request(&self, lobby_id: Option<LobbyId>, error: String) {
+        self.steam_bevy_channel
+            .tx
+            .send(ChannelPacket::LobbyRequestRefused(LobbyJoinFailed {
+                lobby_id,
+                error,
+            }))
+            .expect("Couldn't send refused lobby request");
+    }
+    pub fn request_lobby_list(&self, filters: LobbyListFilters) {
+        let tx = self.steam_bevy_channel.tx.clone();
+        self.lobby_service.request_lobby_list(
+            &filters,
+            Box::new(move |result| {
+                let _ = tx.send(ChannelPacket::LobbyList(result));
+            }),
+        );
+    }
+    pub fn set_lobby_data(&self, key: &str, value: &str) -> Result<(), String> {
+        let lobby_id = self.get_owned_lobby_id()?;
+        if !self.lobby_service.set_lobby_data(lobby_id, key, value) {
+            return Err(format!("Couldn't set lobby data {}", key));
+        }
+        Ok(())
+    }
+    pub fn lobby_data(&self, key: &str) -> Option<String> {
+        let lobby_id = self.get_lobby_id().ok()?;
+        self.lobby_service.lobby_data(lobby_id, key)
+    }
+    pub fn set_member_data(&self, key: &str, value: &str) -> Result<(), String> {
+        let lobby_id = self.get_lobby_id()?;
+        if !self.lobby_service.set_member_data(lobby_id, key, value) {
+            return Err(format!("Couldn't set member data {}", key));
+        }
+        Ok(())
+    }
+    pub fn member_data(&self, member: SteamId, key: &str) -> Option<String> {
+        let lobby_id = self.get_lobby_id().ok()?;
+        self.lobby_service.member_data(lobby_id, member, key)
+    }
+    pub fn set_lobby_data_as<T: Serialize>(&self, key: &str, value: &T) -> Result<(), String> {
+        self.set_lobby_data(key, &encode_data(value)?)
+    }
+    pub fn lobby_data_as<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, String> {
+        self.lobby_data(key)
+            .map(|data| decode_data(&data))
+            .transpose()
+    }
+    pub fn set_member_data_as<T: Serialize>(&self, key: &str, value: &T) -> Result<(), String> {
+        self.set_member_data(key, &encode_data(value)?)
+    }
+    pub fn member_data_as<T: DeserializeOwned>(
+        &self,
+        member: SteamId,
+        key: &str,
+    ) -> Result<Option<T>, String> {
+        self.member_data(member, key)
+            .map(|data| decode_data(&data))
+            .transpose()
+    }
+    pub fn send_lobby_chat(&self, text: &str) -> Result<(), String> {
+        let lobby_id = self.get_lobby_id()?;
+        self.lobby_service
+            .send_lobby_chat(lobby_id, text.as_bytes())
+    }
+    pub fn invite_friend(&self, friend: SteamId) -> Result<(), String> {
+        let lobby_id = self.get_lobby_id()?;
+        self.steam_client
+            .friends()
+            .get_friend(friend)
+            .invite_user_to_game(&connect_string(lobby_id));
+        Ok(())
+    }
+    pub fn open_invite_dialog(&self) -> Result<(), String> {
+        let lobby_id = self.get_lobby_id()?;
+        self.steam_client.friends().activate_invite_dialog(lobby_id);
+        Ok(())
+    }
+    pub fn kick(&self, member: SteamId, reason: &str) -> Result<(), String> {
+        self.get_owned_lobby_id()?;
+        if member == self.id {
+            return Err("Can't kick yourself".to_string());
+        }
+        return self.send_message(
+            &NetworkData::Kick(reason.to_string()),
+            member,
+            SendFlags::RELIABLE,
+        );
+    }
+    //Bans last until unban, even across lobbies
+    pub fn ban(&self, member: SteamId) -> Result<(), String> {
+        self.get_owned_lobby_id()?;
+        if member == self.id {
+            return Err("Can't ban yourself".to_string());
+        }
+        self.session_gate
+            .write()
+            .expect("Session gate poisoned")
+            .banned
+            .insert(member);
+        let _ = self.kick(member, "Banned");
+        Ok(())
+    }
+    pub fn unban(&self, member: SteamId) {
+        self.session_gate
+            .write()
+            .expect("Session gate poisoned")
+            .banned
+            .remove(&member);
+    }
+    pub fn is_banned(&self, member: SteamId) -> bool {
+        self.session_gate
+            .read()
+            .expect("Session gate poisoned")
+            .banned
+            .contains(&member)
+    }
+    pub fn check_session(&self, remote: SteamId) -> Result<(), SessionRejectReason> {
+        self.session_gate
+            .read()
+            .expect("Session gate poisoned")
+            .check(remote, self.lobby_service.as_ref(), &self.steam_client)
+    }
     pub fn leave_lobby(&mut self) {
         let LobbyStatus::InLobby(lobby) = self.lobby_status else {
             return;
         };
         println!("Leave");
         self.steam_client.matchmaking().leave_lobby(lobby);
-        self.lobby_status = LobbyStatus::OutOfLobby;
+        self.lobby_status = LobbyStatus::Leaving;
+        self.session_gate
+            .write()
+            .expect("Session gate poisoned")
+            .lobby = None;
         self.steam_bevy_channel
             .tx
             .send(ChannelPacket::LobbyLeft)
@@ -80,10 +304,16 @@ impl SteamP2PClient {
     }
     pub fn send_message_others(&self, data: NetworkData, flags: SendFlags) -> Result<(), String> {
         let lobby_id = self.get_lobby_id()?;
-        for player in self.steam_client.matchmaking().lobby_members(lobby_id) {
+        let relevant_entity = data.relevant_entity();
+        for player in self.lobby_service.lobby_members(lobby_id) {
             if player == self.id {
                 continue;
             }
+            //Peers the entity isn't relevant to don't have it
+            if relevant_entity.is_some_and(|network_id| !self.relevancy.reaches(network_id, player))
+            {
+                continue;
+            }
             self.send_message(&data, player, flags)
                 .expect("Couldn't send message in send others");
         }
@@ -100,26 +330,43 @@ impl SteamP2PClient {
         &self,
         data: &NetworkData,
         target: SteamId,
-        _flags: SendFlags,
+        flags: SendFlags,
     ) -> Result<(), String> {
         if !self.is_in_lobby() {
             return Err("Not in a lobby".to_string());
         };
         let serialize_data = rmp_serde::to_vec(&data);
         let serialized = serialize_data.map_err(|err| err.to_string())?;
-        let data_arr = serialized.as_slice();
+        return self.send_serialized(serialized.as_slice(), target, flags);
+    }
+    pub(crate) fn send_serialized(
+        &self,
+        data: &[u8],
+        target: SteamId,
+        flags: SendFlags,
+    ) -> Result<(), String> {
+        if !self.is_in_lobby() {
+            return Err("Not in a lobby".to_string());
+        };
+        let send_type = if flags.contains(SendFlags::RELIABLE) {
+            SendType::Reliable
+        } else if flags.contains(SendFlags::NO_DELAY) {
+            SendType::UnreliableNoDelay
+        } else {
+            SendType::Unreliable
+        };
         self.steam_client
             .networking()
-            .send_p2p_packet(target, SendType::Reliable, data_arr);
-        return Ok(());
+            .send_p2p_packet(target, send_type, data);
+        Ok(())
     }
     pub fn get_lobby_member_count(&self) -> Result<usize, String> {
         let lobby_id = self.get_lobby_id()?;
-        let info = self.steam_client.matchmaking().lobby_member_count(lobby_id);
+        let info = self.lobby_service.lobby_member_count(lobby_id);
         return Ok(info);
     }
     pub fn is_in_lobby(&self) -> bool {
-        return self.lobby_status != LobbyStatus::OutOfLobby;
+        return matches!(self.lobby_status, LobbyStatus::InLobby(_));
     }
     pub fn is_lobby_owner(&self) -> Result<bool, String> {
         let owner = self.get_lobby_owner()?;
@@ -128,14 +375,25 @@ impl SteamP2PClient {
     pub fn get_lobby_id(&self) -> Result<LobbyId, String> {
         match self.lobby_status {
             LobbyStatus::InLobby(lobby_id) => return Ok(lobby_id),
-            LobbyStatus::OutOfLobby => return Err("Out of lobby".to_owned()),
+            _ => return Err("Out of lobby".to_owned()),
+        }
+    }
+    fn get_owned_lobby_id(&self) -> Result<LobbyId, String> {
+        let lobby_id = self.get_lobby_id()?;
+        if !self.is_lobby_owner()? {
+            return Err("Only the lobby owner can change the lobby".to_string());
         }
+        return Ok(lobby_id);
     }
     pub fn get_lobby_owner(&self) -> Result<SteamId, String> {
         let lobby_id = self.get_lobby_id()?;
-        let owner = self.steam_client.matchmaking().lobby_owner(lobby_id);
+        let owner = self.lobby_service.lobby_owner(lobby_id);
         return Ok(owner);
     }
+    pub fn get_lobby_members(&self) -> Result<Vec<SteamId>, String> {
+        let lobby_id = self.get_lobby_id()?;
+        return Ok(self.lobby_service.lobby_members(lobby_id));
+    }
     pub fn instantiate(
         &mut self,
         path: FilePath,
@@ -144,16 +402,80 @@ impl SteamP2PClient {
     ) -> Result<NetworkIdentity, String> {
         let network_identity = self.generate_new_network_identity(path, parent_id);
         let clone = network_identity.clone();
-        self.send_message_all(
-            NetworkData::Instantiate(InstantiationData {
-                network_identity,
-                starting_transform,
-            }),
-            SendFlags::RELIABLE,
-        )
-        .expect("Couldn't send instantiate message to all");
+        let data = NetworkData::Instantiate(InstantiationData {
+            network_identity,
+            starting_transform,
+            replicated: Vec::new(),
+        });
+        //Peers get it from the relevancy checks once it's relevant to them
+        if self.relevancy.is_filtered() {
+            self.steam_bevy_channel
+                .tx
+                .send(ChannelPacket::NetworkPacket(NetworkPacket {
+                    data,
+                    sender: self.id,
+                }))
+                .expect("Couldn't send instantiate message to self");
+            return Ok(clone);
+        }
+        self.send_message_all(data, SendFlags::RELIABLE)
+            .expect("Couldn't send instantiate message to all");
         Ok(clone)
     }
+    pub fn destroy(&self, network_identity: &NetworkIdentity) -> Result<(), String> {
+        if network_identity.owner != self.id {
+            return Err("Can't destroy an entity owned by another player".to_string());
+        }
+        return self.send_message_all(
+            NetworkData::Destroy(network_identity.clone()),
+            SendFlags::RELIABLE,
+        );
+    }
+    pub fn request_ownership(&self, network_identity: &NetworkIdentity) -> Result<(), String> {
+        if network_identity.owner == self.id {
+            return Ok(());
+        }
+        let members = self.get_lobby_members()?;
+        let arbiter = if members.contains(&network_identity.owner) {
+            network_identity.owner
+        } else {
+            self.get_lobby_owner()?
+        };
+        let data = NetworkData::OwnershipRequest(network_identity.id);
+        if arbiter == self.id {
+            self.steam_bevy_channel
+                .tx
+                .send(ChannelPacket::NetworkPacket(NetworkPacket {
+                    data,
+                    sender: self.id,
+                }))
+                .expect("Couldn't send ownership request");
+            return Ok(());
+        }
+        return self.send_message(&data, arbiter, SendFlags::RELIABLE);
+    }
+    pub fn transfer_ownership(
+        &self,
+        network_identity: &NetworkIdentity,
+        new_owner: SteamId,
+    ) -> Result<(), String> {
+        if network_identity.owner != self.id && !self.is_lobby_owner()? {
+            return Err("Only the owner or the host can transfer ownership".to_string());
+        }
+        return self.send_message_all(
+            NetworkData::OwnershipChanged(network_identity.id, new_owner),
+            SendFlags::RELIABLE,
+        );
+    }
+    pub(crate) fn is_ownership_arbiter(&self, network_identity: &NetworkIdentity) -> bool {
+        if network_identity.owner == self.id {
+            return true;
+        }
+        let Ok(members) = self.get_lobby_members() else {
+            return false;
+        };
+        return !members.contains(&network_identity.owner) && self.is_lobby_owner() == Ok(true);
+    }
     pub fn get_new_instantiation_id(&mut self) -> NetworkId {
         let id = self.instantiation_id;
         self.instantiation_id += 1;
@@ -171,19 +493,42 @@ impl SteamP2PClient {
             id: self.get_new_instantiation_id(),
             parent_id,
             instantiation_path: path,
+            owner: self.id,
         }
     }
-    pub fn add_to_instantiation_queue(&mut self, instantiation_data: InstantiationData) {
-        self.queued_instantiations.push(instantiation_data);
+    pub fn add_to_instantiation_queue(
+        &mut self,
+        instantiation_data: InstantiationData,
+        deadline: Duration,
+    ) {
+        self.queued_instantiations.push(QueuedInstantiation {
+            data: instantiation_data,
+            deadline,
+        });
     }
-    pub fn get_instantiation_queue(&mut self) -> &mut Vec<InstantiationData> {
+    pub fn get_instantiation_queue(&mut self) -> &mut Vec<QueuedInstantiation> {
         &mut self.queued_instantiations
     }
+    pub fn queued_instantiations(&self) -> &[QueuedInstantiation] {
+        &self.queued_instantiations
+    }
+}
+
+//Instantiation waiting for its parent, given up on once Time::elapsed passes the deadline
+#[derive(Clone, Debug)]
+pub struct QueuedInstantiation {
+    pub data: InstantiationData,
+    pub deadline: Duration,
 }
 
 pub(crate) enum ChannelPacket {
     LobbyJoined(LobbyId),
     LobbyLeft,
+    LobbyList(Result<Vec<LobbyInfo>, String>),
+    LobbyJoinFailed(LobbyJoinFailed),
+    LobbyRequestRefused(LobbyJoinFailed),
+    LobbySettings(LobbySettingsUpdate),
+    SessionRejected(SessionRejected),
     NetworkPacket(NetworkPacket),
 }
 
@@ -192,8 +537,11 @@ pub(crate) struct SteamBevyChannel {
     pub rx: Receiver<ChannelPacket>,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum LobbyStatus {
     InLobby(LobbyId),
     OutOfLobby,
+    Creating,
+    Joining(LobbyId),
+    Leaving,
 }
@@ -0,0 +1,119 @@
+use bevy::{platform::collections::HashSet, prelude::*};
+
+use crate::{
+    client::SteamP2PClient, entity_map::NetworkEntityMap, NetworkId, NetworkIdentity, SteamId,
+};
+
+pub struct NetworkDespawnPlugin;
+
+impl Plugin for NetworkDespawnPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DestroyedIds>()
+            .add_message::<NetworkDestroy>()
+            .add_message::<NetworkDespawned>()
+            .add_systems(Update, handle_destroy);
+    }
+}
+
+#[derive(Message)]
+pub(crate) struct NetworkDestroy {
+    pub network_identity: NetworkIdentity,
+    pub sender: SteamId,
+}
+
+//Ids destroyed during this lobby, so a late Instantiate can't bring them back
+#[derive(Resource, Default)]
+pub(crate) struct DestroyedIds(HashSet<NetworkId>);
+
+impl DestroyedIds {
+    pub fn contains(&self, network_id: &NetworkId) -> bool {
+        self.0.contains(network_id)
+    }
+
+    pub fn insert(&mut self, network_id: NetworkId) {
+        self.0.insert(network_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+#[derive(Message, Clone, Debug)]
+pub struct NetworkDespawned(pub NetworkIdentity);
+
+pub trait DespawnNetworked {
+    fn despawn_networked(&mut self) -> &mut Self;
+}
+
+impl DespawnNetworked for EntityCommands<'_> {
+    fn despawn_networked(&mut self) -> &mut Self {
+        self.queue(|entity: EntityWorldMut| {
+            let Some(network_identity) = entity.get::<NetworkIdentity>() else {
+                println!("Tried to despawn a non networked entity with despawn_networked");
+                return;
+            };
+            let Some(client) = entity.world().get_resource::<SteamP2PClient>() else {
+                return;
+            };
+            if let Err(err) = client.destroy(network_identity) {
+                println!("Couldn't despawn networked entity: {}", err);
+            }
+        });
+        self
+    }
+}
+
+fn handle_destroy(
+    mut client: ResMut<SteamP2PClient>,
+    mut evs_destroy: MessageReader<NetworkDestroy>,
+    mut evs_despawned: MessageWriter<NetworkDespawned>,
+    mut destroyed_ids: ResMut<DestroyedIds>,
+    entity_map: Res<NetworkEntityMap>,
+    networked_query: Query<&NetworkIdentity>,
+    children_query: Query<&Children>,
+    mut commands: Commands,
+) {
+    for ev in evs_destroy.read() {
+        let Some((root, Ok(network_identity))) = entity_map
+            .get(ev.network_identity.id)
+            .map(|entity| (entity, networked_query.get(entity)))
+        else {
+            //Not spawned here yet, remember it in case the Instantiate is still on its way
+            if ev.network_identity.owner == ev.sender {
+                let network_id = ev.network_identity.id;
+                destroyed_ids.insert(network_id);
+                //It may be queued waiting for its parent, where a late joiner's snapshot would find it
+                client.get_instantiation_queue().retain(|queued| {
+                    queued.data.network_identity.id != network_id
+                        && queued.data.network_identity.parent_id != Some(network_id)
+                });
+            }
+            continue;
+        };
+        if network_identity.owner != ev.sender {
+            println!(
+                "Rejected destroy of {:?} from {:?}, who doesn't own it",
+                ev.network_identity.id, ev.sender
+            );
+            continue;
+        }
+        //Networked children are in the parent's hierarchy and go with it
+        let mut destroyed: Vec<NetworkId> = Vec::new();
+        for entity in std::iter::once(root).chain(children_query.iter_descendants(root)) {
+            let Ok(network_identity) = networked_query.get(entity) else {
+                continue;
+            };
+            destroyed_ids.insert(network_identity.id);
+            destroyed.push(network_identity.id);
+            evs_despawned.write(NetworkDespawned(network_identity.clone()));
+        }
+        commands.entity(root).try_despawn();
+        client.get_instantiation_queue().retain(|queued| {
+            match queued.data.network_identity.parent_id {
+                Some(parent) => !destroyed.contains(&parent),
+                None => true,
+            }
+        });
+    }
+}
@@ -0,0 +1,118 @@
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::{ecs::system::SystemParam, platform::collections::HashMap, prelude::*};
+
+use crate::{
+    despawn::DestroyedIds, entity_map::NetworkEntityMap, LobbyLeft, NetworkData, NetworkId,
+    NetworkPacket,
+};
+
+//State updates for entities that haven't spawned yet, replayed once they do
+#[derive(Resource)]
+pub struct EarlyUpdateBuffer {
+    pub capacity: usize, //Packets kept per NetworkId, the oldest are dropped first
+    pub max_entities: usize, //NetworkIds buffered at once, new ones are dropped when full
+    pub max_age: Duration, //Packets older than this are dropped
+    entries: HashMap<NetworkId, VecDeque<(Duration, NetworkPacket)>>,
+}
+
+impl Default for EarlyUpdateBuffer {
+    fn default() -> Self {
+        Self {
+            capacity: 16,
+            max_entities: 1024,
+            max_age: Duration::from_secs(5),
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl EarlyUpdateBuffer {
+    pub(crate) fn push(&mut self, network_id: NetworkId, packet: NetworkPacket, now: Duration) {
+        if !self.entries.contains_key(&network_id) && self.entries.len() >= self.max_entities {
+            return;
+        }
+        let capacity = self.capacity;
+        let packets = self.entries.entry(network_id).or_default();
+        packets.push_back((now, packet));
+        while packets.len() > capacity {
+            packets.pop_front();
+        }
+    }
+
+    pub fn len(&self, network_id: NetworkId) -> usize {
+        self.entries
+            .get(&network_id)
+            .map_or(0, |packets| packets.len())
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl NetworkData {
+    //The entity a packet needs to exist before it can be applied
+    pub(crate) fn target_entity(&self) -> Option<NetworkId> {
+        match self {
+            NetworkData::NetworkedAction(network_identity, _, _)
+            | NetworkData::TransformUpdate(network_identity, _, _, _) => Some(network_identity.id),
+            NetworkData::CompactTransformUpdate(network_id, _)
+            | NetworkData::ComponentUpdate(network_id, _, _)
+            | NetworkData::ComponentRemoved(network_id, _)
+            | NetworkData::OwnershipChanged(network_id, _)
+            | NetworkData::Reparent(network_id, _) => Some(*network_id),
+            _ => None,
+        }
+    }
+}
+
+#[derive(SystemParam)]
+pub(crate) struct EarlyUpdates<'w> {
+    buffer: ResMut<'w, EarlyUpdateBuffer>,
+    entity_map: Res<'w, NetworkEntityMap>,
+    destroyed: Res<'w, DestroyedIds>,
+    time: Res<'w, Time>,
+}
+
+impl EarlyUpdates<'_> {
+    //Returns true if the packet was held back because its entity doesn't exist yet
+    pub fn hold(&mut self, packet: &NetworkPacket) -> bool {
+        let Some(network_id) = packet.data.target_entity() else {
+            return false;
+        };
+        if self.entity_map.contains(network_id) {
+            return false;
+        }
+        if !self.destroyed.contains(&network_id) {
+            self.buffer
+                .push(network_id, packet.clone(), self.time.elapsed());
+        }
+        true
+    }
+}
+
+pub(crate) fn replay_early_updates(
+    mut buffer: ResMut<EarlyUpdateBuffer>,
+    mut evs_left: MessageReader<LobbyLeft>,
+    mut evs_network: MessageWriter<NetworkPacket>,
+    entity_map: Res<NetworkEntityMap>,
+    time: Res<Time>,
+) {
+    if evs_left.read().count() > 0 {
+        buffer.clear();
+        return;
+    }
+    let now = time.elapsed();
+    let max_age = buffer.max_age;
+    buffer.entries.retain(|network_id, packets| {
+        if entity_map.contains(*network_id) {
+            for (_, packet) in packets.drain(..) {
+                evs_network.write(packet);
+            }
+            return false;
+        }
+        packets.retain(|(received, _)| now.saturating_sub(*received) < max_age);
+        !packets.is_empty()
+    });
+}
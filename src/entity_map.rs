@@ -0,0 +1,61 @@
+use bevy::{
+    ecs::{lifecycle::HookContext, world::DeferredWorld},
+    platform::collections::HashMap,
+    prelude::*,
+};
+
+use crate::{NetworkId, NetworkIdentity};
+
+//Kept up to date by the NetworkIdentity insert/replace hooks
+#[derive(Resource, Default)]
+pub struct NetworkEntityMap {
+    entities: HashMap<NetworkId, Entity>,
+}
+
+impl NetworkEntityMap {
+    pub fn get(&self, network_id: NetworkId) -> Option<Entity> {
+        self.entities.get(&network_id).copied()
+    }
+
+    pub fn contains(&self, network_id: NetworkId) -> bool {
+        self.entities.contains_key(&network_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&NetworkId, &Entity)> {
+        self.entities.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+pub(crate) fn on_network_identity_insert(mut world: DeferredWorld, context: HookContext) {
+    let Some(network_id) = world
+        .get::<NetworkIdentity>(context.entity)
+        .map(|network_identity| network_identity.id)
+    else {
+        return;
+    };
+    if let Some(mut map) = world.get_resource_mut::<NetworkEntityMap>() {
+        map.entities.insert(network_id, context.entity);
+    }
+}
+
+pub(crate) fn on_network_identity_replace(mut world: DeferredWorld, context: HookContext) {
+    let Some(network_id) = world
+        .get::<NetworkIdentity>(context.entity)
+        .map(|network_identity| network_identity.id)
+    else {
+        return;
+    };
+    if let Some(mut map) = world.get_resource_mut::<NetworkEntityMap>() {
+        if map.entities.get(&network_id) == Some(&context.entity) {
+            map.entities.remove(&network_id);
+        }
+    }
+}
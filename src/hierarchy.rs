@@ -0,0 +1,88 @@
+use bevy::prelude::*;
+use steamworks::networking_types::SendFlags;
+
+use crate::{
+    client::SteamP2PClient, entity_map::NetworkEntityMap, NetworkData, NetworkId, NetworkIdentity,
+    SteamId,
+};
+
+pub struct NetworkHierarchyPlugin;
+
+impl Plugin for NetworkHierarchyPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<NetworkReparent>()
+            .add_systems(Update, (send_reparents, handle_reparents));
+    }
+}
+
+#[derive(Message)]
+pub(crate) struct NetworkReparent {
+    pub network_id: NetworkId,
+    pub parent_id: Option<NetworkId>,
+    pub sender: SteamId,
+}
+
+fn send_reparents(
+    client: Res<SteamP2PClient>,
+    changed_query: Query<Entity, (Changed<ChildOf>, With<NetworkIdentity>)>,
+    mut removed: RemovedComponents<ChildOf>,
+    mut networked_query: Query<(&mut NetworkIdentity, Option<&ChildOf>)>,
+) {
+    let mut candidates: Vec<Entity> = changed_query.iter().collect();
+    candidates.extend(removed.read());
+    for entity in candidates {
+        let Ok((network_identity, child_of)) = networked_query.get(entity) else {
+            continue;
+        };
+        if network_identity.owner != client.id {
+            continue;
+        }
+        //Parents that aren't networked can't be replicated, the child is unparented for others
+        let parent_id = child_of
+            .and_then(|child_of| networked_query.get(child_of.parent()).ok())
+            .map(|(parent_identity, _)| parent_identity.id);
+        if parent_id == network_identity.parent_id {
+            continue;
+        }
+        let network_id = network_identity.id;
+        if let Ok((mut network_identity, _)) = networked_query.get_mut(entity) {
+            network_identity.parent_id = parent_id;
+        }
+        let _ = client.send_message_others(
+            NetworkData::Reparent(network_id, parent_id),
+            SendFlags::RELIABLE,
+        );
+    }
+}
+
+fn handle_reparents(
+    mut evs_reparent: MessageReader<NetworkReparent>,
+    entity_map: Res<NetworkEntityMap>,
+    mut networked_query: Query<&mut NetworkIdentity>,
+    mut commands: Commands,
+) {
+    for ev in evs_reparent.read() {
+        let Some(entity) = entity_map.get(ev.network_id) else {
+            continue;
+        };
+        let Ok(mut network_identity) = networked_query.get_mut(entity) else {
+            continue;
+        };
+        if network_identity.owner != ev.sender {
+            println!(
+                "Rejected reparent of {:?} from {:?}, who doesn't own it",
+                ev.network_id, ev.sender
+            );
+            continue;
+        }
+        network_identity.parent_id = ev.parent_id;
+        match ev.parent_id.and_then(|parent_id| entity_map.get(parent_id)) {
+            Some(parent) => {
+                commands.entity(entity).insert(ChildOf(parent));
+            }
+            None => {
+                commands.entity(entity).remove::<ChildOf>();
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use bevy::prelude::*;
+
+use crate::{
+    client::SteamP2PClient, lobby::LobbyService, networked_transform::NetworkedTransform,
+    ownership::OwnershipChanged, LobbyJoined, LobbyLeft, NetworkIdentity, SteamId,
+};
+use bevy_steamworks::LobbyId;
+
+pub struct HostMigrationPlugin;
+
+impl Plugin for HostMigrationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HostTracker>()
+            .add_message::<HostMigrated>()
+            .add_systems(Update, handle_host_migration);
+    }
+}
+
+#[derive(Message, Clone, Copy, Debug, PartialEq)]
+pub struct HostMigrated {
+    pub old: SteamId,
+    pub new: SteamId,
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct HostTracker {
+    pub host: Option<SteamId>,
+}
+
+impl HostTracker {
+    pub fn detect(
+        &mut self,
+        lobby_service: &dyn LobbyService,
+        lobby: LobbyId,
+    ) -> Option<HostMigrated> {
+        let current = lobby_service.lobby_owner(lobby);
+        let previous = self.host.replace(current)?;
+        if previous == current {
+            return None;
+        }
+        Some(HostMigrated {
+            old: previous,
+            new: current,
+        })
+    }
+}
+
+//Entities of a host that left are handed to the new host instead of being despawned
+pub(crate) fn hand_off(
+    migration: &HostMigrated,
+    members: &[SteamId],
+    network_identity: &mut NetworkIdentity,
+) -> bool {
+    if network_identity.owner != migration.old || members.contains(&migration.old) {
+        return false;
+    }
+    network_identity.owner = migration.new;
+    true
+}
+
+fn handle_host_migration(
+    client: Res<SteamP2PClient>,
+    mut tracker: ResMut<HostTracker>,
+    mut evs_joined: MessageReader<LobbyJoined>,
+    mut evs_left: MessageReader<LobbyLeft>,
+    mut evs_migrated: MessageWriter<HostMigrated>,
+    mut evs_ownership: MessageWriter<OwnershipChanged>,
+    mut networked_query: Query<(
+        &mut NetworkIdentity,
+        Option<&Transform>,
+        Option<&mut NetworkedTransform>,
+    )>,
+) {
+    if evs_joined.read().count() > 0 || evs_left.read().count() > 0 {
+        tracker.host = None;
+    }
+    let Ok(lobby_id) = client.get_lobby_id() else {
+        tracker.host = None;
+        return;
+    };
+    let Some(migration) = tracker.detect(client.lobby_service.as_ref(), lobby_id) else {
+        return;
+    };
+    println!(
+        "Host migrated from {:?} to {:?}",
+        migration.old, migration.new
+    );
+    let members = client.lobby_service.lobby_members(lobby_id);
+    for (mut network_identity, transform, networked_transform) in networked_query.iter_mut() {
+        if !hand_off(&migration, &members, &mut network_identity) {
+            continue;
+        }
+        if let (Some(transform), Some(mut networked_transform)) = (transform, networked_transform) {
+            networked_transform.reset_to(transform);
+        }
+        evs_ownership.write(OwnershipChanged {
+            network_identity: network_identity.clone(),
+            old_owner: migration.old,
+            new_owner: migration.new,
+        });
+    }
+    evs_migrated.write(migration);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{FilePath, NetworkId};
+
+    struct MockLobby {
+        owner: Mutex<SteamId>,
+        members: Mutex<Vec<SteamId>>,
+    }
+
+    impl LobbyService for MockLobby {
+        fn lobby_owner(&self, _lobby: LobbyId) -> SteamId {
+            *self.owner.lock().unwrap()
+        }
+
+        fn lobby_members(&self, _lobby: LobbyId) -> Vec<SteamId> {
+            self.members.lock().unwrap().clone()
+        }
+    }
+
+    fn identity(owner: SteamId, index: u32) -> NetworkIdentity {
+        NetworkIdentity {
+            id: NetworkId { owner, index },
+            parent_id: None,
+            instantiation_path: FilePath::new("Test"),
+            owner,
+        }
+    }
+
+    #[test]
+    fn detects_new_host_after_owner_leaves() {
+        let (a, b, c) = (
+            SteamId::from_raw(1),
+            SteamId::from_raw(2),
+            SteamId::from_raw(3),
+        );
+        let lobby = LobbyId::from_raw(10);
+        let service = MockLobby {
+            owner: Mutex::new(a),
+            members: Mutex::new(vec![a, b, c]),
+        };
+        let mut tracker = HostTracker::default();
+        assert_eq!(tracker.detect(&service, lobby), None);
+        assert_eq!(tracker.detect(&service, lobby), None);
+
+        *service.owner.lock().unwrap() = b;
+        service
+            .members
+            .lock()
+            .unwrap()
+            .retain(|member| *member != a);
+        assert_eq!(
+            tracker.detect(&service, lobby),
+            Some(HostMigrated { old: a, new: b })
+        );
+        assert_eq!(tracker.detect(&service, lobby), None);
+    }
+
+    #[test]
+    fn hands_off_only_entities_of_the_departed_host() {
+        let (a, b, c) = (
+            SteamId::from_raw(1),
+            SteamId::from_raw(2),
+            SteamId::from_raw(3),
+        );
+        let migration = HostMigrated { old: a, new: b };
+        let members = vec![b, c];
+        let mut host_owned = identity(a, 0);
+        let mut other_owned = identity(c, 0);
+        assert!(hand_off(&migration, &members, &mut host_owned));
+        assert_eq!(host_owned.owner, b);
+        assert_eq!(host_owned.id.owner, a);
+        assert!(!hand_off(&migration, &members, &mut other_owned));
+        assert_eq!(other_owned.owner, c);
+
+        //Host changed without leaving, nothing is handed off
+        let mut still_present = identity(a, 1);
+        assert!(!hand_off(&migration, &[a, b, c], &mut still_present));
+        assert_eq!(still_present.owner, a);
+    }
+}
@@ -0,0 +1,125 @@
+use bevy::prelude::*;
+use bevy_steamworks::LobbyId;
+
+use crate::{client::SteamP2PClient, LobbyJoined, LobbyLeft, SteamId};
+
+pub struct InvitePlugin;
+
+impl Plugin for InvitePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<JoinRequestPolicy>()
+            .add_message::<JoinRequested>()
+            .add_message::<LaunchJoinRequested>()
+            .add_systems(Startup, read_launch_parameters)
+            .add_systems(Update, (handle_join_requests, update_rich_presence));
+    }
+}
+
+//What happens when the player accepts an invite or joins a friend from the friends list
+#[derive(Resource, Default, PartialEq, Clone, Copy, Debug)]
+pub enum JoinRequestPolicy {
+    #[default]
+    Automatic,
+    Manual, //Read JoinRequested and LaunchJoinRequested and call join_lobby yourself
+}
+
+#[derive(Message, Clone, Copy, Debug)]
+pub struct JoinRequested {
+    pub lobby_id: LobbyId,
+    pub friend: Option<SteamId>, //Whose invite or presence it came from, if Steam says
+}
+
+//The game was started, or sent new launch parameters, to join this lobby
+#[derive(Message, Clone, Copy, Debug)]
+pub struct LaunchJoinRequested {
+    pub lobby_id: LobbyId,
+}
+
+const CONNECT_PREFIX: &str = "+connect_lobby";
+
+pub fn connect_string(lobby_id: LobbyId) -> String {
+    format!("{} {}", CONNECT_PREFIX, lobby_id.raw())
+}
+
+//Reads the lobby out of a "+connect_lobby <id>" string, as sent by invites and rich presence
+pub fn parse_connect_string(connect: &str) -> Option<LobbyId> {
+    let mut parts = connect.split_whitespace();
+    while let Some(part) = parts.next() {
+        if part == CONNECT_PREFIX {
+            return parts.next()?.parse::<u64>().ok().map(LobbyId::from_raw);
+        }
+    }
+    None
+}
+
+fn launch_lobby(client: &SteamP2PClient) -> Option<LobbyId> {
+    let args = std::env::args().collect::<Vec<String>>().join(" ");
+    //Launches through a steam://run URL put their arguments here instead
+    parse_connect_string(&args)
+        .or_else(|| parse_connect_string(&client.steam_client.apps().launch_command_line()))
+}
+
+fn read_launch_parameters(
+    client: Res<SteamP2PClient>,
+    mut evs_launch: MessageWriter<LaunchJoinRequested>,
+) {
+    if let Some(lobby_id) = launch_lobby(&client) {
+        evs_launch.write(LaunchJoinRequested { lobby_id });
+    }
+}
+
+fn handle_join_requests(
+    mut client: ResMut<SteamP2PClient>,
+    policy: Res<JoinRequestPolicy>,
+    mut evs_join: MessageReader<JoinRequested>,
+    mut evs_launch: MessageReader<LaunchJoinRequested>,
+) {
+    let requested = evs_join
+        .read()
+        .map(|ev| ev.lobby_id)
+        .chain(evs_launch.read().map(|ev| ev.lobby_id))
+        .collect::<Vec<LobbyId>>();
+    for lobby_id in requested {
+        println!("Join requested: {}", lobby_id.raw());
+        if *policy == JoinRequestPolicy::Manual || client.get_lobby_id() == Ok(lobby_id) {
+            continue;
+        }
+        client.leave_lobby();
+        client.join_lobby(lobby_id);
+    }
+}
+
+//Lets friends join from their friends list
+fn update_rich_presence(
+    client: Res<SteamP2PClient>,
+    mut evs_joined: MessageReader<LobbyJoined>,
+    mut evs_left: MessageReader<LobbyLeft>,
+) {
+    let friends = client.steam_client.friends();
+    if evs_left.read().count() > 0 {
+        friends.clear_rich_presence();
+    }
+    for ev in evs_joined.read() {
+        friends.set_rich_presence("connect", Some(&connect_string(ev.lobby_id)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_string_round_trips() {
+        let lobby_id = LobbyId::from_raw(109775241021923245);
+        assert_eq!(
+            parse_connect_string(&connect_string(lobby_id)),
+            Some(lobby_id)
+        );
+        assert_eq!(
+            parse_connect_string("-novid +connect_lobby 42 +other"),
+            Some(LobbyId::from_raw(42))
+        );
+        assert_eq!(parse_connect_string("+connect_lobby"), None);
+        assert_eq!(parse_connect_string("+connect 127.0.0.1"), None);
+    }
+}
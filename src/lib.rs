@@ -1,18 +1,65 @@
-use bevy::prelude::*;
+use bandwidth::BandwidthPlugin;
+use std::time::Duration;
+
+use bevy::{
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+};
 use bevy_steamworks::*;
+use chat::LobbyChatReceived;
+use despawn::{DestroyedIds, NetworkDespawnPlugin, NetworkDestroy};
+use early_updates::{replay_early_updates, EarlyUpdateBuffer, EarlyUpdates};
+use entity_map::{on_network_identity_insert, on_network_identity_replace, NetworkEntityMap};
 use flume::{Receiver, Sender};
+use hierarchy::{NetworkHierarchyPlugin, NetworkReparent};
+use host_migration::{HostMigrationPlugin, HostTracker};
+use invites::{parse_connect_string, InvitePlugin, JoinRequested, LaunchJoinRequested};
+use lobby::{LobbyDataChanged, LobbyListReceived, LobbyServiceOverride};
+use lobby_settings::{LobbySettingsPlugin, LobbySettingsUpdate};
+use moderation::{KickNotice, ModerationPlugin};
 use networked_messages::register::{NetworkedMessageRegister, NetworkedMessagesPlugin};
-use networked_movable::{NetworkedMovable, NetworkedMovablePlugin};
-use networked_transform::{NetworkedTransform, NetworkedTransformPlugin, TransformUpdate};
+use networked_movable::NetworkedMovablePlugin;
+use networked_transform::{
+    CompactTransform, NetworkedTransform, NetworkedTransformPlugin, TransformState, TransformUpdate,
+};
+use ownership::{
+    return_borrowed, OwnershipChanged, OwnershipPlugin, OwnershipRequest, OwnershipTransfer,
+};
+use prefab::{NetworkPrefabPlugin, NetworkPrefabRegister};
+use relevancy::{RelevancyNotice, RelevancyPlugin};
+use replication::{ComponentReplication, ReplicationPlugin, ReplicationRegister};
+use scene::{NetworkScenePlugin, PendingSceneInstantiations};
 use serde::{Deserialize, Serialize};
+use session::{SessionPlugin, SessionRejected};
+use state::NetworkStatePlugin;
 use steamworks::networking_types::NetConnectionEnd;
 
+pub mod bandwidth;
+pub mod chat;
+#[cfg(feature = "chat_panel")]
+pub mod chat_panel;
 pub mod client;
+pub mod despawn;
+pub mod early_updates;
+pub mod entity_map;
+pub mod hierarchy;
+pub mod host_migration;
+pub mod invites;
+pub mod lobby;
+pub mod lobby_settings;
+pub mod moderation;
 pub mod networked_messages;
-mod networked_movable;
+pub mod networked_movable;
 pub mod networked_transform;
+pub mod ownership;
+pub mod prefab;
 pub mod prelude;
-pub use client::SteamP2PClient;
+pub mod relevancy;
+pub mod replication;
+pub mod scene;
+pub mod session;
+pub mod state;
+pub use client::{QueuedInstantiation, SteamP2PClient};
 pub use steamworks::{networking_types::SendFlags, SteamId};
 
 use crate::client::{ChannelPacket, LobbyStatus};
@@ -21,11 +68,29 @@ pub struct SteamP2PPlugin;
 impl Plugin for SteamP2PPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(SteamworksPlugin::init_app(480).unwrap())
+            .init_resource::<NetworkEntityMap>()
+            .init_resource::<EarlyUpdateBuffer>()
             .add_plugins((
                 NetworkedMessagesPlugin,
+                BandwidthPlugin,
+                ReplicationPlugin,
+                NetworkDespawnPlugin,
+                OwnershipPlugin,
+                HostMigrationPlugin,
+                NetworkPrefabPlugin,
+                NetworkScenePlugin,
+                NetworkHierarchyPlugin,
+                RelevancyPlugin,
                 NetworkedMovablePlugin,
                 NetworkedTransformPlugin,
             ))
+            .add_plugins((
+                LobbySettingsPlugin,
+                InvitePlugin,
+                ModerationPlugin,
+                SessionPlugin,
+                NetworkStatePlugin,
+            ))
             .add_systems(PreStartup, steam_start)
             .add_systems(
                 Update,
@@ -33,6 +98,11 @@ impl Plugin for SteamP2PPlugin {
                     handle_channels,
                     steam_events,
                     receive_messages,
+                    //Buffered updates are older than anything received this frame
+                    replay_early_updates
+                        .before(receive_messages)
+                        .before(handle_channels)
+                        .before(handle_network_data),
                     handle_network_data,
                     handle_instantiate,
                     handle_queued_instantiations,
@@ -42,8 +112,17 @@ impl Plugin for SteamP2PPlugin {
             .add_message::<LobbyJoined>()
             .add_message::<NetworkPacket>()
             .add_message::<UnhandledInstantiation>()
+            .add_message::<DuplicateNetworkId>()
+            .add_message::<OrphanedInstantiation>()
+            .init_resource::<InstantiationQueueSettings>()
+            .init_resource::<DuplicateInstantiationPolicy>()
             .add_message::<LobbyLeft>()
+            .add_message::<LobbyJoinFailed>()
+            .add_message::<LobbyListReceived>()
+            .add_message::<LobbyDataChanged>()
+            .add_message::<LobbyChatReceived>()
             .add_message::<OtherJoined>()
+            .add_message::<InitialSyncComplete>()
             .add_message::<NetworkedAction>()
             .add_message::<NetworkInstantiation>();
     }
@@ -54,12 +133,24 @@ pub struct LobbyJoined {
     pub lobby_id: LobbyId,
 }
 
+//Creating or joining a lobby didn't work, or was refused because we're already in or entering one
+#[derive(Message, Clone, Debug)]
+pub struct LobbyJoinFailed {
+    pub lobby_id: Option<LobbyId>, //None when creating
+    pub error: String,
+}
+
 #[derive(Message)]
 pub struct OtherJoined(pub SteamId);
 
 #[derive(Message)]
 pub struct LobbyLeft;
 
+//Everything that existed when we joined has been received, from every member when relevancy is filtered.
+//Fired right away for the lobby creator
+#[derive(Message)]
+pub struct InitialSyncComplete;
+
 #[derive(Message)]
 pub struct NetworkedAction {
     pub network_identity: NetworkIdentity,
@@ -73,23 +164,65 @@ pub struct NetworkInstantiation(pub InstantiationData);
 #[derive(Message)]
 pub struct UnhandledInstantiation(pub InstantiationData);
 
+#[derive(Message, Clone, Debug)]
+pub struct OrphanedInstantiation(pub InstantiationData);
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OrphanPolicy {
+    Discard,
+    SpawnUnparented,
+}
+
+//How long instantiations wait for their parent before being given up on
+#[derive(Resource, Clone, Debug)]
+pub struct InstantiationQueueSettings {
+    pub timeout: Duration,
+    pub orphan_policy: OrphanPolicy,
+}
+
+impl Default for InstantiationQueueSettings {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            orphan_policy: OrphanPolicy::Discard,
+        }
+    }
+}
+
+//Sent under ReportConflict when an instantiation arrives for a NetworkId that exists or is pending
+#[derive(Message, Clone, Debug)]
+pub struct DuplicateNetworkId {
+    pub data: InstantiationData,
+    pub existing: Option<Entity>, //None while the existing one is still queued or loading
+}
+
+#[derive(Resource, Default, PartialEq, Clone, Copy, Debug)]
+pub enum DuplicateInstantiationPolicy {
+    #[default]
+    Ignore,
+    UpdateExisting, //Apply the duplicate's identity, transform, parent and replicated state to the existing one
+    ReportConflict, //Keep the existing one and send DuplicateNetworkId
+}
+
 #[derive(Message, Clone, Debug)]
 pub struct NetworkPacket {
     pub data: NetworkData,
     pub sender: SteamId,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct NetworkId {
     pub owner: SteamId,
     pub index: u32,
 }
 
 #[derive(Component, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[component(on_insert = on_network_identity_insert, on_replace = on_network_identity_replace)]
 pub struct NetworkIdentity {
     pub id: NetworkId,
     pub parent_id: Option<NetworkId>,
     pub instantiation_path: FilePath,
+    pub owner: SteamId, //Current authority, starts as id.owner and changes with ownership transfers
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -99,6 +232,10 @@ impl FilePath {
     pub fn new(path: &str) -> FilePath {
         FilePath(path.to_string())
     }
+
+    pub fn is_scene(&self) -> bool {
+        self.0.ends_with(".scn.ron")
+    }
 }
 
 impl std::cmp::PartialEq<&str> for FilePath {
@@ -114,71 +251,214 @@ pub enum NetworkData {
     NetworkedAction(NetworkIdentity, u8, Vec<u8>), //NetworkId of receiver, id of action, data of action
     Instantiate(InstantiationData), //NetworkId of created object, optional network id of parent, starting position
     TransformUpdate(NetworkIdentity, Option<Vec3>, Option<Quat>, Option<Vec3>), //NetworkId of receiver, new position
-    Destroy(NetworkIdentity), //NetworkId of object to be destroyed
+    CompactTransformUpdate(NetworkId, CompactTransform), //NetworkId of receiver, quantized position and rotation
+    ComponentUpdate(NetworkId, u8, Vec<u8>), //NetworkId of receiver, index of replicated component, serialized component
+    ComponentRemoved(NetworkId, u8),         //NetworkId of receiver, index of removed component
+    Destroy(NetworkIdentity),                //NetworkId of object to be destroyed
+    OwnershipRequest(NetworkId),             //NetworkId of object the sender wants to own
+    OwnershipChanged(NetworkId, SteamId),    //NetworkId of object, new owner
+    Reparent(NetworkId, Option<NetworkId>),  //NetworkId of object, network id of its new parent
+    OutOfRelevance(NetworkId), //NetworkId of object to despawn until it's relevant again
+    ObserverPositions(Vec<Vec3>), //Positions of the sender's NetworkObservers
     NetworkMessage(String), //Message for arbitrary communication, to be avoided outside of development
     DebugMessage(String),   //Make the receiving client print the message
+    InitialSyncComplete,    //End of the late-join snapshot
+    Kick(String),           //Sent by the lobby owner to the kicked member, with the reason
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InstantiationData {
     pub network_identity: NetworkIdentity,
     pub starting_transform: Transform,
+    #[serde(default)]
+    pub replicated: Vec<(u8, Vec<u8>)>, //Replicated components to insert on spawn, by register index
+}
+
+impl InstantiationData {
+    pub(crate) fn from_entity(entity: &EntityRef, register: &ReplicationRegister) -> Self {
+        InstantiationData {
+            network_identity: entity.get::<NetworkIdentity>().unwrap().clone(),
+            starting_transform: entity.get::<Transform>().copied().unwrap_or_default(),
+            replicated: register.snapshot(entity),
+        }
+    }
 }
 
 fn handle_joiner(
-    client: ResMut<SteamP2PClient>,
+    mut client: ResMut<SteamP2PClient>,
+    register: Res<ReplicationRegister>,
+    pending_scenes: Res<PendingSceneInstantiations>,
+    destroyed: Res<DestroyedIds>,
     mut evs: MessageReader<OtherJoined>,
-    networked_query: Query<(&NetworkIdentity, Option<&Transform>)>,
+    networked_query: Query<EntityRef, With<NetworkIdentity>>,
 ) {
     for OtherJoined(id) in evs.read() {
         println!("Somebody joined your lobby: {:?}", id);
-        if client.is_lobby_owner().unwrap() {
-            for (networked, transform) in networked_query.iter() {
-                println!("Replicate: {:?}", networked);
-                client
-                    .send_message(
-                        &NetworkData::Instantiate(InstantiationData {
-                            network_identity: networked.clone(),
-                            starting_transform: *transform.unwrap_or(&Transform::default()),
-                        }),
-                        *id,
-                        SendFlags::RELIABLE,
-                    )
-                    .expect("Couldn't send data to joiner");
-            }
+        //With relevancy filtering every owner sends the joiner what's relevant to them,
+        //and its own InitialSyncComplete after the first relevancy pass that knows where the joiner is
+        if client.relevancy.is_filtered() {
+            client.relevancy.defer_sync(*id);
+            continue;
+        }
+        if client.is_lobby_owner() != Ok(true) {
+            continue;
         }
+        let mut snapshot: Vec<InstantiationData> = networked_query
+            .iter()
+            .map(|entity| InstantiationData::from_entity(&entity, &register))
+            .collect();
+        //Children still waiting for their parent here are still part of the world
+        snapshot.extend(
+            client
+                .get_instantiation_queue()
+                .iter()
+                .map(|queued| queued.data.clone()),
+        );
+        snapshot.extend(pending_scenes.0.iter().map(|(data, _)| data.clone()));
+        //Destroyed before they spawned here, the joiner would never hear about the Destroy
+        snapshot.retain(|data| !destroyed.contains(&data.network_identity.id));
+        for data in order_by_hierarchy(snapshot) {
+            println!("Replicate: {:?}", data.network_identity);
+            client
+                .send_message(&NetworkData::Instantiate(data), *id, SendFlags::RELIABLE)
+                .expect("Couldn't send data to joiner");
+        }
+        client
+            .send_message(&NetworkData::InitialSyncComplete, *id, SendFlags::RELIABLE)
+            .expect("Couldn't send sync complete to joiner");
     }
 }
 
+//Parents come before their children so the joiner never has to queue them
+fn order_by_hierarchy(snapshot: Vec<InstantiationData>) -> Vec<InstantiationData> {
+    let ids: HashSet<NetworkId> = snapshot.iter().map(|d| d.network_identity.id).collect();
+    let mut children: HashMap<NetworkId, Vec<InstantiationData>> = HashMap::new();
+    let mut ordered: Vec<InstantiationData> = Vec::with_capacity(snapshot.len());
+    for data in snapshot {
+        match data
+            .network_identity
+            .parent_id
+            .filter(|parent| ids.contains(parent))
+        {
+            Some(parent) => children.entry(parent).or_default().push(data),
+            None => ordered.push(data),
+        }
+    }
+    let mut index = 0;
+    while index < ordered.len() {
+        if let Some(mut next) = children.remove(&ordered[index].network_identity.id) {
+            ordered.append(&mut next);
+        }
+        index += 1;
+    }
+    //Cycles never hang off a root, they go last
+    ordered.extend(children.into_values().flatten());
+    ordered
+}
+
+#[allow(clippy::too_many_arguments)]
 fn handle_instantiate(
     mut client: ResMut<SteamP2PClient>,
     mut evs_network: MessageReader<NetworkInstantiation>,
     mut evs_unhandled: MessageWriter<UnhandledInstantiation>,
-    networked_query: Query<&NetworkIdentity>,
+    entity_map: Res<NetworkEntityMap>,
+    prefabs: Res<NetworkPrefabRegister>,
+    mut pending_scenes: ResMut<PendingSceneInstantiations>,
+    asset_server: Res<AssetServer>,
+    register: Res<ReplicationRegister>,
+    destroyed: Res<DestroyedIds>,
+    duplicate_policy: Res<DuplicateInstantiationPolicy>,
+    mut evs_duplicate: MessageWriter<DuplicateNetworkId>,
+    queue_settings: Res<InstantiationQueueSettings>,
+    time: Res<Time>,
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
+    //The entity map only sees spawns once commands are applied
+    let mut spawned: HashMap<NetworkId, Entity> = HashMap::new();
     for NetworkInstantiation(data) in evs_network.read() {
-        if let Some(parent_id) = &data.network_identity.parent_id {
-            if !networked_query
-                .iter()
-                .any(|n| n.id.owner == data.network_identity.id.owner && n.id == *parent_id)
-            {
-                client.add_to_instantiation_queue(data.clone());
+        let network_id = data.network_identity.id;
+        if destroyed.contains(&network_id) {
+            continue;
+        }
+        let existing = entity_map
+            .get(network_id)
+            .or_else(|| spawned.get(&network_id).copied());
+        let queued = client
+            .get_instantiation_queue()
+            .iter()
+            .any(|q| q.data.network_identity.id == network_id);
+        if existing.is_some() || queued || pending_scenes.contains(network_id) {
+            match *duplicate_policy {
+                DuplicateInstantiationPolicy::Ignore => {}
+                DuplicateInstantiationPolicy::ReportConflict => {
+                    println!("Conflicting instantiation for {:?}", network_id);
+                    evs_duplicate.write(DuplicateNetworkId {
+                        data: data.clone(),
+                        existing,
+                    });
+                }
+                DuplicateInstantiationPolicy::UpdateExisting => match existing {
+                    Some(entity) => {
+                        let mut entity_commands = commands.entity(entity);
+                        entity_commands
+                            .insert((data.starting_transform, data.network_identity.clone()));
+                        //Parents that aren't here yet keep the current hierarchy
+                        match data.network_identity.parent_id {
+                            Some(parent_id) => {
+                                if let Some(parent) = entity_map
+                                    .get(parent_id)
+                                    .or_else(|| spawned.get(&parent_id).copied())
+                                {
+                                    entity_commands.insert(ChildOf(parent));
+                                }
+                            }
+                            None => {
+                                entity_commands.remove::<ChildOf>();
+                            }
+                        }
+                        register.apply_snapshot(&data.replicated, &mut entity_commands);
+                    }
+                    None => {
+                        for pending in client
+                            .get_instantiation_queue()
+                            .iter_mut()
+                            .map(|queued| &mut queued.data)
+                            .chain(pending_scenes.0.iter_mut().map(|(pending, _)| pending))
+                            .filter(|pending| pending.network_identity.id == network_id)
+                        {
+                            *pending = data.clone();
+                        }
+                    }
+                },
+            }
+            continue;
+        }
+        if let Some(parent_id) = data.network_identity.parent_id {
+            //Parents sent earlier in the same snapshot were only spawned this frame
+            if !entity_map.contains(parent_id) && !spawned.contains_key(&parent_id) {
+                client.add_to_instantiation_queue(
+                    data.clone(),
+                    time.elapsed() + queue_settings.timeout,
+                );
                 continue;
             }
         }
-        //TODO: Add scene support once it comes out
-        if data.network_identity.instantiation_path == "InstantiationExample" {
-            commands.spawn((
-                Mesh3d(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
-                MeshMaterial3d(materials.add(Color::srgb_u8(124, 144, 255))),
-                data.starting_transform,
-                data.network_identity.clone(),
-                NetworkedTransform::default(),
-                NetworkedMovable { speed: 10. },
-            ));
+        if let Some(spawner) = prefabs.get(&data.network_identity.instantiation_path) {
+            let entity = spawner(&mut commands, data);
+            spawned.insert(network_id, entity);
+            let mut entity_commands = commands.entity(entity);
+            entity_commands.insert((data.starting_transform, data.network_identity.clone()));
+            //starting_transform is relative to the parent
+            if let Some(parent) = data
+                .network_identity
+                .parent_id
+                .and_then(|p| entity_map.get(p).or_else(|| spawned.get(&p).copied()))
+            {
+                entity_commands.insert(ChildOf(parent));
+            }
+            register.apply_snapshot(&data.replicated, &mut entity_commands);
+        } else if data.network_identity.instantiation_path.is_scene() {
+            pending_scenes.add(data.clone(), &asset_server);
         } else {
             evs_unhandled.write(UnhandledInstantiation(data.clone()));
         }
@@ -188,30 +468,62 @@ fn handle_instantiate(
 fn handle_queued_instantiations(
     mut client: ResMut<SteamP2PClient>,
     mut evs_network: MessageWriter<NetworkInstantiation>,
-    networked_query: Query<&NetworkIdentity>,
+    mut evs_orphaned: MessageWriter<OrphanedInstantiation>,
+    entity_map: Res<NetworkEntityMap>,
+    queue_settings: Res<InstantiationQueueSettings>,
+    time: Res<Time>,
 ) {
+    let now = time.elapsed();
     client.get_instantiation_queue().retain(|queued| {
-        if networked_query.iter().any(|n| {
-            n.id.owner == queued.network_identity.id.owner
-                && n.id == queued.network_identity.parent_id.clone().unwrap()
-        }) {
-            evs_network.write(NetworkInstantiation(queued.clone()));
+        //UpdateExisting can replace a queued entry with one that has no parent
+        if queued
+            .data
+            .network_identity
+            .parent_id
+            .is_none_or(|parent_id| entity_map.contains(parent_id))
+        {
+            evs_network.write(NetworkInstantiation(queued.data.clone()));
+            return false;
+        }
+        if now >= queued.deadline {
+            println!(
+                "Parent of {:?} never arrived",
+                queued.data.network_identity.id
+            );
+            evs_orphaned.write(OrphanedInstantiation(queued.data.clone()));
+            if queue_settings.orphan_policy == OrphanPolicy::SpawnUnparented {
+                let mut data = queued.data.clone();
+                data.network_identity.parent_id = None;
+                evs_network.write(NetworkInstantiation(data));
+            }
             return false;
         }
         return true;
     });
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_network_data(
     mut commands: Commands,
     mut evs_network: MessageReader<NetworkPacket>,
     mut ev_pos_update: MessageWriter<TransformUpdate>,
     mut ev_network_instantiation: MessageWriter<NetworkInstantiation>,
     mut ev_networked_action: MessageWriter<NetworkedAction>,
+    mut ev_component_replication: MessageWriter<ComponentReplication>,
+    mut ev_destroy: MessageWriter<NetworkDestroy>,
+    mut ev_ownership_request: MessageWriter<OwnershipRequest>,
+    mut ev_ownership_transfer: MessageWriter<OwnershipTransfer>,
     register: Res<NetworkedMessageRegister>,
     mut other_joined_w: MessageWriter<OtherJoined>,
+    mut reparent_w: MessageWriter<NetworkReparent>,
+    mut relevancy_w: MessageWriter<RelevancyNotice>,
+    mut kick_w: MessageWriter<KickNotice>,
+    mut early_updates: EarlyUpdates,
 ) {
     for ev in evs_network.read() {
+        if early_updates.hold(ev) {
+            continue;
+        }
         match ev.data.clone() {
             NetworkData::NetworkedAction(id, action_id, action_data) => {
                 ev_networked_action.write(NetworkedAction {
@@ -222,17 +534,85 @@ fn handle_network_data(
             }
             NetworkData::TransformUpdate(id, position, rotation, scale) => {
                 ev_pos_update.write(TransformUpdate {
-                    network_identity: id,
-                    position,
-                    rotation,
-                    scale,
+                    network_id: id.id,
+                    state: TransformState::Full(position, rotation, scale),
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::CompactTransformUpdate(id, compact) => {
+                ev_pos_update.write(TransformUpdate {
+                    network_id: id,
+                    state: TransformState::Compact(compact),
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::ComponentUpdate(id, index, data) => {
+                ev_component_replication.write(ComponentReplication {
+                    network_id: id,
+                    index,
+                    data: Some(data),
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::ComponentRemoved(id, index) => {
+                ev_component_replication.write(ComponentReplication {
+                    network_id: id,
+                    index,
+                    data: None,
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::Destroy(network_identity) => {
+                ev_destroy.write(NetworkDestroy {
+                    network_identity,
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::OwnershipRequest(id) => {
+                ev_ownership_request.write(OwnershipRequest {
+                    network_id: id,
+                    requester: ev.sender,
+                });
+            }
+            NetworkData::OwnershipChanged(id, new_owner) => {
+                ev_ownership_transfer.write(OwnershipTransfer {
+                    network_id: id,
+                    new_owner,
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::Reparent(id, parent_id) => {
+                reparent_w.write(NetworkReparent {
+                    network_id: id,
+                    parent_id,
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::OutOfRelevance(id) => {
+                relevancy_w.write(RelevancyNotice::Lost {
+                    network_id: id,
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::ObserverPositions(positions) => {
+                relevancy_w.write(RelevancyNotice::Observers {
+                    positions,
+                    sender: ev.sender,
+                });
+            }
+            NetworkData::Kick(reason) => {
+                kick_w.write(KickNotice {
+                    reason,
+                    sender: ev.sender,
                 });
             }
-            NetworkData::Destroy(_) => println!("Destroyed"),
             NetworkData::OtherJoined(id) => {
                 println!("Other joined: {:?}", id);
                 other_joined_w.write(OtherJoined(id));
             }
+            NetworkData::InitialSyncComplete => {
+                relevancy_w.write(RelevancyNotice::SyncComplete { sender: ev.sender });
+            }
             NetworkData::DebugMessage(message) => {
                 println!("Debug message from {:?}: {}", ev.sender, message)
             }
@@ -248,7 +628,36 @@ fn handle_network_data(
     }
 }
 
-fn receive_messages(client: Res<SteamP2PClient>, mut evs_network: MessageWriter<NetworkPacket>) {
+//Steam's member list can lag behind a joiner's first packets, those are held this long
+const UNKNOWN_SENDER_GRACE: Duration = Duration::from_secs(5);
+const MAX_UNKNOWN_SENDER_PACKETS: usize = 256;
+
+fn receive_messages(
+    client: Res<SteamP2PClient>,
+    mut evs_network: MessageWriter<NetworkPacket>,
+    mut unknown_senders: Local<Vec<(Duration, NetworkPacket)>>,
+    time: Res<Time>,
+) {
+    //The gate learns about the lobby as soon as Steam does, before LobbyJoined is handled
+    let lobby = client
+        .session_gate
+        .read()
+        .expect("Session gate poisoned")
+        .lobby;
+    let members = lobby
+        .map(|lobby| client.lobby_service.lobby_members(lobby))
+        .unwrap_or_default();
+    let now = time.elapsed();
+    for (received, packet) in std::mem::take(&mut *unknown_senders) {
+        if client.is_banned(packet.sender) {
+            continue;
+        }
+        if members.contains(&packet.sender) {
+            evs_network.write(packet);
+        } else if lobby.is_some() && now.saturating_sub(received) < UNKNOWN_SENDER_GRACE {
+            unknown_senders.push((received, packet));
+        }
+    }
     while client
         .steam_client
         .networking()
@@ -259,19 +668,33 @@ fn receive_messages(client: Res<SteamP2PClient>, mut evs_network: MessageWriter<
         let Some((sender, _)) = client.steam_client.networking().read_p2p_packet(&mut buf) else {
             break;
         };
+        if client.is_banned(sender) {
+            continue;
+        }
         let data_try: Result<NetworkData, _> = rmp_serde::from_slice(&buf);
 
         if let Ok(data) = data_try {
-            evs_network.write(NetworkPacket { sender, data });
+            if members.contains(&sender) {
+                evs_network.write(NetworkPacket { sender, data });
+            } else if lobby.is_some() && unknown_senders.len() < MAX_UNKNOWN_SENDER_PACKETS {
+                unknown_senders.push((now, NetworkPacket { sender, data }));
+            }
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_channels(
     mut client: ResMut<SteamP2PClient>,
     mut evs_joined: MessageWriter<LobbyJoined>,
     mut evs_network: MessageWriter<NetworkPacket>,
     mut evs_left: MessageWriter<LobbyLeft>,
+    mut evs_join_failed: MessageWriter<LobbyJoinFailed>,
+    mut evs_sync_complete: MessageWriter<InitialSyncComplete>,
+    mut evs_lobby_list: MessageWriter<LobbyListReceived>,
+    mut evs_lobby_settings: MessageWriter<LobbySettingsUpdate>,
+    mut evs_rejected: MessageWriter<SessionRejected>,
+    mut destroyed: ResMut<DestroyedIds>,
     mut commands: Commands,
     networked_query: Query<Entity, With<NetworkIdentity>>,
 ) {
@@ -286,15 +709,55 @@ fn handle_channels(
                         SendFlags::RELIABLE,
                     )
                     .expect("Couldn't send other joined message");
+                //Nobody is there to send us a snapshot when we created the lobby
+                if client.is_lobby_owner() == Ok(true) {
+                    evs_sync_complete.write(InitialSyncComplete);
+                } else if client.relevancy.is_filtered() {
+                    let own_id = client.id;
+                    let members = client.get_lobby_members().unwrap_or_default();
+                    client
+                        .relevancy
+                        .await_sync(members.into_iter().filter(|member| *member != own_id));
+                } else if let Ok(host) = client.get_lobby_owner() {
+                    client.relevancy.await_sync([host]);
+                }
                 println!("Joined Lobby: {}", lobby_id.raw());
             }
             ChannelPacket::LobbyLeft => {
+                //We may already be creating or joining the next lobby
+                if client.lobby_status == LobbyStatus::Leaving {
+                    client.lobby_status = LobbyStatus::OutOfLobby;
+                }
                 evs_left.write(LobbyLeft);
+                destroyed.clear();
+                client.get_instantiation_queue().clear();
                 for entity in networked_query.iter() {
                     commands.entity(entity).despawn();
                 }
                 println!("Left Lobby")
             }
+            ChannelPacket::LobbyJoinFailed(failed) => {
+                client.lobby_status = LobbyStatus::OutOfLobby;
+                println!("{}", failed.error);
+                evs_join_failed.write(failed);
+            }
+            ChannelPacket::LobbyRequestRefused(refused) => {
+                println!("{}", refused.error);
+                evs_join_failed.write(refused);
+            }
+            ChannelPacket::LobbyList(result) => {
+                evs_lobby_list.write(LobbyListReceived(result));
+            }
+            ChannelPacket::LobbySettings(update) => {
+                evs_lobby_settings.write(update);
+            }
+            ChannelPacket::SessionRejected(rejected) => {
+                println!(
+                    "Rejected session from {:?}: {:?}",
+                    rejected.remote, rejected.reason
+                );
+                evs_rejected.write(rejected);
+            }
             ChannelPacket::NetworkPacket(network_packet) => {
                 evs_network.write(network_packet);
             }
@@ -302,18 +765,49 @@ fn handle_channels(
     }
 }
 
-fn steam_start(steam_client: Res<Client>, mut commands: Commands) {
+fn steam_start(
+    steam_client: Res<Client>,
+    lobby_service: Option<Res<LobbyServiceOverride>>,
+    mut commands: Commands,
+) {
     let steam_id = steam_client.user().steam_id();
     println!("Connected: {}", steam_id.raw());
     steam_client.networking_utils().init_relay_network_access();
+    let mut client = SteamP2PClient::new(steam_client.clone());
+    //Set before anything clones it, the session callback below keeps its own copy
+    if let Some(lobby_service) = lobby_service {
+        client = client.with_lobby_service(lobby_service.0.clone());
+    }
+    let session_gate = client.session_gate.clone();
+    let lobby_service = client.lobby_service.clone();
+    let tx = client.steam_bevy_channel.tx.clone();
+    let callback_client = steam_client.clone();
     steam_client
         .networking_messages()
         .session_request_callback(move |session_request| {
-            if session_request.remote().steam_id() == Some(steam_id) {
+            let Some(remote) = session_request.remote().steam_id() else {
+                session_request.reject();
+                return;
+            };
+            if remote == steam_id {
                 session_request.accept();
                 return;
             }
-            session_request.accept();
+            let check = session_gate.read().expect("Session gate poisoned").check(
+                remote,
+                lobby_service.as_ref(),
+                &callback_client,
+            );
+            match check {
+                Ok(()) => session_request.accept(),
+                Err(reason) => {
+                    session_request.reject();
+                    let _ = tx.send(ChannelPacket::SessionRejected(SessionRejected {
+                        remote,
+                        reason,
+                    }));
+                }
+            }
         });
     steam_client
         .networking_messages()
@@ -328,31 +822,79 @@ fn steam_start(steam_client: Res<Client>, mut commands: Commands) {
                 res.end_reason().unwrap_or(NetConnectionEnd::Other(-42))
             );
         });
-    commands.insert_resource(SteamP2PClient::new(steam_client.clone()));
+    commands.insert_resource(client);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn steam_events(
     mut msgs: MessageReader<SteamworksEvent>,
     client: Res<SteamP2PClient>,
-    network_query: Query<(Entity, &NetworkIdentity)>,
+    host_tracker: Res<HostTracker>,
+    mut network_query: Query<(
+        Entity,
+        &mut NetworkIdentity,
+        Option<&Transform>,
+        Option<&mut NetworkedTransform>,
+    )>,
+    mut evs_ownership: MessageWriter<OwnershipChanged>,
+    mut evs_data_changed: MessageWriter<LobbyDataChanged>,
+    mut evs_chat: MessageWriter<LobbyChatReceived>,
+    mut evs_join: MessageWriter<JoinRequested>,
+    mut evs_launch: MessageWriter<LaunchJoinRequested>,
+    mut evs_rejected: MessageWriter<SessionRejected>,
     mut commands: Commands,
 ) {
     for ev in msgs.read().map(|SteamworksEvent::CallbackResult(a)| a) {
         match ev {
             CallbackResult::GameLobbyJoinRequested(info) => {
-                println!("Trying to join: {}", info.lobby_steam_id.raw());
-                client.join_lobby(info.lobby_steam_id)
+                evs_join.write(JoinRequested {
+                    lobby_id: info.lobby_steam_id,
+                    friend: Some(info.friend_steam_id),
+                });
             }
             CallbackResult::LobbyChatUpdate(info) => match info.member_state_change {
                 ChatMemberStateChange::Entered => {
                     println!("Other joined lobby !!!");
+                    if client.is_banned(info.making_change) {
+                        let _ = client.kick(info.making_change, "Banned");
+                    }
                 }
                 ChatMemberStateChange::Left | ChatMemberStateChange::Disconnected => {
                     println!("Other left lobby");
-                    for (entity, networked) in network_query.iter() {
-                        if networked.id.owner == info.making_change {
+                    //The host's entities are handed to the new host by handle_host_migration
+                    if host_tracker.host == Some(info.making_change) {
+                        continue;
+                    }
+                    let Ok(host) = client.get_lobby_owner() else {
+                        continue;
+                    };
+                    let members = client.get_lobby_members().unwrap_or_default();
+                    //What the leaver created leaves with them, what they picked up is given back
+                    for (entity, mut network_identity, transform, networked_transform) in
+                        network_query.iter_mut()
+                    {
+                        if network_identity.id.owner == info.making_change {
                             commands.entity(entity).despawn();
+                            continue;
                         }
+                        if !return_borrowed(
+                            info.making_change,
+                            &members,
+                            host,
+                            &mut network_identity,
+                        ) {
+                            continue;
+                        }
+                        if let (Some(transform), Some(mut networked_transform)) =
+                            (transform, networked_transform)
+                        {
+                            networked_transform.reset_to(transform);
+                        }
+                        evs_ownership.write(OwnershipChanged {
+                            network_identity: network_identity.clone(),
+                            old_owner: info.making_change,
+                            new_owner: network_identity.owner,
+                        });
                     }
                 }
                 _ => println!("Lobby chat update: {:?}", info),
@@ -362,6 +904,13 @@ fn steam_events(
             CallbackResult::DownloadItemResult(_) => println!("Download item result"),
             CallbackResult::P2PSessionConnectFail(_) => println!("P2P Fail"),
             CallbackResult::P2PSessionRequest(request) => {
+                if let Err(reason) = client.check_session(request.remote) {
+                    evs_rejected.write(SessionRejected {
+                        remote: request.remote,
+                        reason,
+                    });
+                    continue;
+                }
                 client
                     .steam_client
                     .networking()
@@ -375,7 +924,18 @@ fn steam_events(
             CallbackResult::UserStatsReceived(_) => println!("UserStatsReceived"),
             CallbackResult::UserStatsStored(_) => println!("User stats stored"),
             CallbackResult::ValidateAuthTicketResponse(_) => println!("Validate auth ticket"),
-            CallbackResult::LobbyChatMsg(_) => println!("Lobby chat message received"),
+            CallbackResult::LobbyChatMsg(msg) => {
+                let Some(entry) = client
+                    .lobby_service
+                    .lobby_chat_entry(msg.lobby, msg.chat_id)
+                else {
+                    continue;
+                };
+                evs_chat.write(LobbyChatReceived {
+                    sender: msg.user,
+                    text: String::from_utf8_lossy(&entry).into_owned(),
+                });
+            }
             CallbackResult::FloatingGamepadTextInputDismissed(_) => {
                 println!("Floating gamepad text input dismissed")
             }
@@ -383,11 +943,28 @@ fn steam_events(
             CallbackResult::GamepadTextInputDismissed(_) => {
                 println!("Gamepad text input dismissed")
             }
-            CallbackResult::GameRichPresenceJoinRequested(_) => {
-                println!("Game rich presence join requested")
+            CallbackResult::GameRichPresenceJoinRequested(info) => {
+                let Some(lobby_id) = parse_connect_string(&info.connect) else {
+                    println!("Couldn't read join request: {}", info.connect);
+                    continue;
+                };
+                evs_join.write(JoinRequested {
+                    lobby_id,
+                    friend: Some(info.friend_steam_id),
+                });
             }
             CallbackResult::LobbyCreated(_) => println!("Lobby created"),
-            CallbackResult::LobbyDataUpdate(_) => println!("Lobby data update"),
+            CallbackResult::LobbyDataUpdate(update) => {
+                if !update.success {
+                    continue;
+                }
+                //Steam reports lobby data changes with the lobby as the member
+                let member = (update.member.raw() != update.lobby.raw()).then_some(update.member);
+                evs_data_changed.write(LobbyDataChanged {
+                    lobby_id: update.lobby,
+                    member,
+                });
+            }
             CallbackResult::LobbyEnter(_) => println!("Lobby enter"),
             CallbackResult::MicroTxnAuthorizationResponse(_) => {
                 println!("MicroTxn authorization response")
@@ -413,7 +990,12 @@ fn steam_events(
             CallbackResult::GSClientDeny(_) => println!("GS client deny"),
             CallbackResult::GSClientKick(_) => println!("GS client kick"),
             CallbackResult::GSClientGroupStatus(_) => println!("GS client group status"),
-            CallbackResult::NewUrlLaunchParameters(_) => println!("New URL launch parameters"),
+            CallbackResult::NewUrlLaunchParameters(_) => {
+                let launch_command_line = client.steam_client.apps().launch_command_line();
+                if let Some(lobby_id) = parse_connect_string(&launch_command_line) {
+                    evs_launch.write(LaunchJoinRequested { lobby_id });
+                }
+            }
         }
     }
 }
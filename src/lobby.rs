@@ -0,0 +1,454 @@
+use std::sync::Arc;
+
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_steamworks::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::lobby_settings::LobbyPrivacy;
+
+pub type LobbyListCallback = Box<dyn FnOnce(Result<Vec<LobbyInfo>, String>) + Send>;
+
+//Lobby queries go through this so they can be served by something other than Steam in tests
+pub trait LobbyService: Send + Sync + 'static {
+    fn lobby_owner(&self, lobby: LobbyId) -> SteamId;
+    fn lobby_members(&self, lobby: LobbyId) -> Vec<SteamId>;
+    fn lobby_member_count(&self, lobby: LobbyId) -> usize {
+        self.lobby_members(lobby).len()
+    }
+    fn request_lobby_list(&self, _filters: &LobbyListFilters, on_result: LobbyListCallback) {
+        on_result(Err(
+            "Lobby lists aren't supported by this lobby service".to_string()
+        ));
+    }
+    fn lobby_data(&self, _lobby: LobbyId, _key: &str) -> Option<String> {
+        None
+    }
+    fn set_lobby_data(&self, _lobby: LobbyId, _key: &str, _value: &str) -> bool {
+        false
+    }
+    fn member_data(&self, _lobby: LobbyId, _member: SteamId, _key: &str) -> Option<String> {
+        None
+    }
+    fn set_member_data(&self, _lobby: LobbyId, _key: &str, _value: &str) -> bool {
+        false
+    }
+    fn set_lobby_privacy(&self, _lobby: LobbyId, _privacy: LobbyPrivacy) -> bool {
+        false
+    }
+    fn set_lobby_joinable(&self, _lobby: LobbyId, _joinable: bool) -> bool {
+        false
+    }
+    fn set_max_members(&self, _lobby: LobbyId, _max_members: u32) -> bool {
+        false
+    }
+    fn send_lobby_chat(&self, _lobby: LobbyId, _message: &[u8]) -> Result<(), String> {
+        Err("Lobby chat isn't supported by this lobby service".to_string())
+    }
+    fn lobby_chat_entry(&self, _lobby: LobbyId, _chat_id: i32) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+//Insert before the app runs to replace Steam's lobbies, the client is created at PreStartup
+#[derive(Resource, Clone)]
+pub struct LobbyServiceOverride(pub Arc<dyn LobbyService>);
+
+impl LobbyServiceOverride {
+    pub fn new(lobby_service: impl LobbyService) -> Self {
+        LobbyServiceOverride(Arc::new(lobby_service))
+    }
+}
+
+pub struct SteamLobbyService(pub Client);
+
+impl LobbyService for SteamLobbyService {
+    fn lobby_owner(&self, lobby: LobbyId) -> SteamId {
+        self.0.matchmaking().lobby_owner(lobby)
+    }
+
+    fn lobby_members(&self, lobby: LobbyId) -> Vec<SteamId> {
+        self.0.matchmaking().lobby_members(lobby)
+    }
+
+    fn lobby_member_count(&self, lobby: LobbyId) -> usize {
+        self.0.matchmaking().lobby_member_count(lobby)
+    }
+
+    fn lobby_data(&self, lobby: LobbyId, key: &str) -> Option<String> {
+        self.0
+            .matchmaking()
+            .lobby_data(lobby, key)
+            .map(|value| value.to_string())
+    }
+
+    fn set_lobby_data(&self, lobby: LobbyId, key: &str, value: &str) -> bool {
+        self.0.matchmaking().set_lobby_data(lobby, key, value)
+    }
+
+    fn member_data(&self, lobby: LobbyId, member: SteamId, key: &str) -> Option<String> {
+        self.0
+            .matchmaking()
+            .get_lobby_member_data(lobby, member, key)
+            .map(|value| value.to_string())
+    }
+
+    fn set_member_data(&self, lobby: LobbyId, key: &str, value: &str) -> bool {
+        self.0
+            .matchmaking()
+            .set_lobby_member_data(lobby, key, value);
+        true
+    }
+
+    fn set_lobby_privacy(&self, lobby: LobbyId, privacy: LobbyPrivacy) -> bool {
+        self.0
+            .matchmaking()
+            .set_lobby_type(lobby, privacy.lobby_type())
+    }
+
+    fn set_lobby_joinable(&self, lobby: LobbyId, joinable: bool) -> bool {
+        self.0.matchmaking().set_lobby_joinable(lobby, joinable)
+    }
+
+    fn set_max_members(&self, lobby: LobbyId, max_members: u32) -> bool {
+        self.0
+            .matchmaking()
+            .set_lobby_member_limit(lobby, max_members as usize)
+    }
+
+    fn send_lobby_chat(&self, lobby: LobbyId, message: &[u8]) -> Result<(), String> {
+        self.0
+            .matchmaking()
+            .send_lobby_chat_message(lobby, message)
+            .map_err(|err| err.to_string())
+    }
+
+    fn lobby_chat_entry(&self, lobby: LobbyId, chat_id: i32) -> Option<Vec<u8>> {
+        //Steam caps chat messages at 4k
+        let mut buffer = [0u8; 4096];
+        let entry = self
+            .0
+            .matchmaking()
+            .get_lobby_chat_entry(lobby, chat_id, &mut buffer);
+        Some(entry.to_vec())
+    }
+
+    fn request_lobby_list(&self, filters: &LobbyListFilters, on_result: LobbyListCallback) {
+        let matchmaking = self.0.matchmaking();
+        for (key, value, comparison) in &filters.strings {
+            matchmaking.set_request_lobby_list_string_filter(StringFilter(
+                LobbyKey::new(key),
+                value,
+                comparison.string_filter(),
+            ));
+        }
+        for (key, value, comparison) in &filters.numbers {
+            matchmaking.set_request_lobby_list_numerical_filter(NumberFilter(
+                LobbyKey::new(key),
+                *value,
+                comparison.number_filter(),
+            ));
+        }
+        for (key, value) in &filters.near {
+            matchmaking
+                .set_request_lobby_list_near_value_filter(NearFilter(LobbyKey::new(key), *value));
+        }
+        if let Some(slots) = filters.slots_available {
+            matchmaking.set_request_lobby_list_slots_available_filter(slots);
+        }
+        if let Some(distance) = filters.distance {
+            matchmaking.set_request_lobby_list_distance_filter(distance.distance_filter());
+        }
+        if let Some(max_results) = filters.max_results {
+            matchmaking.set_request_lobby_list_result_count_filter(max_results);
+        }
+        let client = self.0.clone();
+        matchmaking.request_lobby_list(move |result| {
+            let lobbies = result.map_err(|err| err.to_string()).map(|lobby_ids| {
+                let matchmaking = client.matchmaking();
+                lobby_ids
+                    .into_iter()
+                    .map(|lobby_id| LobbyInfo {
+                        lobby_id,
+                        data: (0..matchmaking.lobby_data_count(lobby_id))
+                            .filter_map(|index| matchmaking.lobby_data_by_index(lobby_id, index))
+                            .collect(),
+                        member_count: matchmaking.lobby_member_count(lobby_id),
+                        member_limit: matchmaking.lobby_member_limit(lobby_id),
+                    })
+                    .collect()
+            });
+            on_result(lobbies);
+        });
+    }
+}
+
+//Steam doesn't say which keys changed, read the ones you care about again
+#[derive(Message, Clone, Debug)]
+pub struct LobbyDataChanged {
+    pub lobby_id: LobbyId,
+    pub member: Option<SteamId>, //None when the lobby's own data changed
+}
+
+//Whole structs are stored as hex encoded msgpack, so they can't be used in lobby list filters
+pub(crate) fn encode_data<T: Serialize>(value: &T) -> Result<String, String> {
+    let bytes = rmp_serde::to_vec(value).map_err(|err| err.to_string())?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+pub(crate) fn decode_data<T: DeserializeOwned>(data: &str) -> Result<T, String> {
+    //Other peers write this, checking the digits first also keeps the byte slicing below on char boundaries
+    if data.len() % 2 != 0 || !data.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err("Lobby data isn't valid hex".to_string());
+    }
+    let bytes = (0..data.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&data[index..index + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|err| err.to_string())?;
+    rmp_serde::from_slice(&bytes).map_err(|err| err.to_string())
+}
+
+#[derive(Message, Clone, Debug)]
+pub struct LobbyListReceived(pub Result<Vec<LobbyInfo>, String>);
+
+#[derive(Clone, Debug)]
+pub struct LobbyInfo {
+    pub lobby_id: LobbyId,
+    pub data: HashMap<String, String>, //Lobby metadata set by its owner
+    pub member_count: usize,
+    pub member_limit: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LobbyComparison {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+}
+
+impl LobbyComparison {
+    fn string_filter(self) -> StringFilterKind {
+        match self {
+            LobbyComparison::Equal => StringFilterKind::Equal,
+            LobbyComparison::NotEqual => StringFilterKind::NotEqual,
+            LobbyComparison::LessThan => StringFilterKind::LessThan,
+            LobbyComparison::LessThanOrEqual => StringFilterKind::EqualToOrLessThan,
+            LobbyComparison::GreaterThan => StringFilterKind::GreaterThan,
+            LobbyComparison::GreaterThanOrEqual => StringFilterKind::EqualToOrGreaterThan,
+        }
+    }
+
+    fn number_filter(self) -> ComparisonFilter {
+        match self {
+            LobbyComparison::Equal => ComparisonFilter::Equal,
+            LobbyComparison::NotEqual => ComparisonFilter::NotEqual,
+            LobbyComparison::LessThan => ComparisonFilter::LessThan,
+            LobbyComparison::LessThanOrEqual => ComparisonFilter::EqualToOrLessThan,
+            LobbyComparison::GreaterThan => ComparisonFilter::GreaterThan,
+            LobbyComparison::GreaterThanOrEqual => ComparisonFilter::EqualToOrGreaterThan,
+        }
+    }
+
+    //The lobby's value is on the left, so LessThan keeps lobbies whose value is below the filter's
+    fn compare<T: PartialOrd>(self, lobby_value: &T, filter_value: &T) -> bool {
+        match self {
+            LobbyComparison::Equal => lobby_value == filter_value,
+            LobbyComparison::NotEqual => lobby_value != filter_value,
+            LobbyComparison::LessThan => lobby_value < filter_value,
+            LobbyComparison::LessThanOrEqual => lobby_value <= filter_value,
+            LobbyComparison::GreaterThan => lobby_value > filter_value,
+            LobbyComparison::GreaterThanOrEqual => lobby_value >= filter_value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LobbyDistance {
+    Close,
+    Default,
+    Far,
+    Worldwide,
+}
+
+impl LobbyDistance {
+    fn distance_filter(self) -> DistanceFilter {
+        match self {
+            LobbyDistance::Close => DistanceFilter::Close,
+            LobbyDistance::Default => DistanceFilter::Default,
+            LobbyDistance::Far => DistanceFilter::Far,
+            LobbyDistance::Worldwide => DistanceFilter::Worldwide,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LobbyListFilters {
+    pub strings: Vec<(String, String, LobbyComparison)>,
+    pub numbers: Vec<(String, i32, LobbyComparison)>,
+    pub near: Vec<(String, i32)>, //Sorts results by closeness to the value
+    pub slots_available: Option<u8>,
+    pub distance: Option<LobbyDistance>,
+    pub max_results: Option<u64>,
+}
+
+impl LobbyListFilters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_string(mut self, key: &str, value: &str, comparison: LobbyComparison) -> Self {
+        self.strings
+            .push((key.to_string(), value.to_string(), comparison));
+        self
+    }
+
+    pub fn with_number(mut self, key: &str, value: i32, comparison: LobbyComparison) -> Self {
+        self.numbers.push((key.to_string(), value, comparison));
+        self
+    }
+
+    pub fn with_near(mut self, key: &str, value: i32) -> Self {
+        self.near.push((key.to_string(), value));
+        self
+    }
+
+    pub fn with_slots_available(mut self, slots: u8) -> Self {
+        self.slots_available = Some(slots);
+        self
+    }
+
+    pub fn with_distance(mut self, distance: LobbyDistance) -> Self {
+        self.distance = Some(distance);
+        self
+    }
+
+    pub fn with_max_results(mut self, max_results: u64) -> Self {
+        self.max_results = Some(max_results);
+        self
+    }
+
+    //For lobby services that filter locally, distance and near values are left to the caller
+    pub fn matches(&self, lobby: &LobbyInfo) -> bool {
+        let strings = self.strings.iter().all(|(key, value, comparison)| {
+            lobby
+                .data
+                .get(key)
+                .is_some_and(|lobby_value| comparison.compare(lobby_value, value))
+        });
+        let numbers = self.numbers.iter().all(|(key, value, comparison)| {
+            lobby
+                .data
+                .get(key)
+                .and_then(|lobby_value| lobby_value.parse::<i32>().ok())
+                .is_some_and(|lobby_value| comparison.compare(&lobby_value, value))
+        });
+        let slots = match (self.slots_available, lobby.member_limit) {
+            (Some(slots), Some(limit)) => {
+                limit.saturating_sub(lobby.member_count) >= slots as usize
+            }
+            _ => true,
+        };
+        strings && numbers && slots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    struct FakeLobbies(Vec<LobbyInfo>);
+
+    impl LobbyService for FakeLobbies {
+        fn lobby_owner(&self, _lobby: LobbyId) -> SteamId {
+            SteamId::from_raw(0)
+        }
+
+        fn lobby_members(&self, _lobby: LobbyId) -> Vec<SteamId> {
+            Vec::new()
+        }
+
+        fn request_lobby_list(&self, filters: &LobbyListFilters, on_result: LobbyListCallback) {
+            let mut lobbies: Vec<LobbyInfo> = self
+                .0
+                .iter()
+                .filter(|lobby| filters.matches(lobby))
+                .cloned()
+                .collect();
+            if let Some(max_results) = filters.max_results {
+                lobbies.truncate(max_results as usize);
+            }
+            on_result(Ok(lobbies));
+        }
+    }
+
+    fn lobby(raw: u64, mode: &str, level: i32, member_count: usize) -> LobbyInfo {
+        LobbyInfo {
+            lobby_id: LobbyId::from_raw(raw),
+            data: HashMap::from_iter([
+                ("mode".to_string(), mode.to_string()),
+                ("level".to_string(), level.to_string()),
+            ]),
+            member_count,
+            member_limit: Some(4),
+        }
+    }
+
+    fn request(service: &dyn LobbyService, filters: LobbyListFilters) -> Vec<u64> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        service.request_lobby_list(
+            &filters,
+            Box::new(move |result| {
+                *sink.lock().unwrap() = result
+                    .unwrap()
+                    .iter()
+                    .map(|lobby| lobby.lobby_id.raw())
+                    .collect();
+            }),
+        );
+        let ids = received.lock().unwrap().clone();
+        ids
+    }
+
+    #[test]
+    fn typed_data_round_trips() {
+        #[derive(Serialize, serde::Deserialize, PartialEq, Debug)]
+        struct Settings {
+            map: String,
+            password_required: bool,
+        }
+        let settings = Settings {
+            map: "Harbor".to_string(),
+            password_required: true,
+        };
+        let encoded = encode_data(&settings).unwrap();
+        assert_eq!(decode_data::<Settings>(&encoded).unwrap(), settings);
+        assert!(decode_data::<Settings>("zz").is_err());
+        //Even length, but the second char is two bytes wide
+        assert!(decode_data::<Settings>("aéb").is_err());
+        assert!(decode_data::<Settings>("+1").is_err());
+    }
+
+    #[test]
+    fn filters_by_metadata_and_slots() {
+        let service = FakeLobbies(vec![
+            lobby(1, "coop", 3, 1),
+            lobby(2, "versus", 5, 2),
+            lobby(3, "coop", 8, 4),
+            lobby(4, "coop", 10, 2),
+        ]);
+        let coop = LobbyListFilters::new().with_string("mode", "coop", LobbyComparison::Equal);
+        assert_eq!(request(&service, coop.clone()), vec![1, 3, 4]);
+        let open_coop = coop.with_slots_available(1);
+        assert_eq!(request(&service, open_coop.clone()), vec![1, 4]);
+        let high_level = open_coop.with_number("level", 5, LobbyComparison::GreaterThanOrEqual);
+        assert_eq!(request(&service, high_level), vec![4]);
+        let limited = LobbyListFilters::new().with_max_results(2);
+        assert_eq!(request(&service, limited), vec![1, 2]);
+    }
+}
@@ -0,0 +1,107 @@
+use bevy::prelude::*;
+use bevy_steamworks::LobbyType;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::SteamP2PClient, lobby::LobbyDataChanged, LobbyJoined, LobbyLeft};
+
+pub struct LobbySettingsPlugin;
+
+impl Plugin for LobbySettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LobbySettings>()
+            .add_message::<LobbySettingsUpdate>()
+            .add_systems(Update, (apply_lobby_settings, sync_lobby_settings));
+    }
+}
+
+//The lobby owner publishes its settings under this lobby data key so members see them too
+pub const LOBBY_SETTINGS_KEY: &str = "bevy_steam_p2p_settings";
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Clone, Copy, Debug)]
+pub enum LobbyPrivacy {
+    Private,
+    FriendsOnly,
+    #[default]
+    Public,
+    Invisible, //Joinable by id but hidden from lobby lists
+}
+
+impl LobbyPrivacy {
+    pub(crate) fn lobby_type(self) -> LobbyType {
+        match self {
+            LobbyPrivacy::Private => LobbyType::Private,
+            LobbyPrivacy::FriendsOnly => LobbyType::FriendsOnly,
+            LobbyPrivacy::Public => LobbyType::Public,
+            LobbyPrivacy::Invisible => LobbyType::Invisible,
+        }
+    }
+}
+
+#[derive(Resource, Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub struct LobbySettings {
+    pub privacy: LobbyPrivacy,
+    pub joinable: bool,
+    pub max_members: u32, //0 outside of a lobby
+}
+
+impl Default for LobbySettings {
+    fn default() -> Self {
+        Self {
+            privacy: LobbyPrivacy::Public,
+            joinable: true,
+            max_members: 0,
+        }
+    }
+}
+
+#[derive(Message, Clone, Copy, Debug)]
+pub(crate) enum LobbySettingsUpdate {
+    Created(LobbySettings),
+    Privacy(LobbyPrivacy),
+    Joinable(bool),
+    MaxMembers(u32),
+}
+
+fn apply_lobby_settings(
+    client: Res<SteamP2PClient>,
+    mut evs_update: MessageReader<LobbySettingsUpdate>,
+    mut settings: ResMut<LobbySettings>,
+) {
+    if evs_update.is_empty() {
+        return;
+    }
+    for ev in evs_update.read() {
+        match *ev {
+            LobbySettingsUpdate::Created(created) => *settings = created,
+            LobbySettingsUpdate::Privacy(privacy) => settings.privacy = privacy,
+            LobbySettingsUpdate::Joinable(joinable) => settings.joinable = joinable,
+            LobbySettingsUpdate::MaxMembers(max_members) => settings.max_members = max_members,
+        }
+    }
+    if let Err(err) = client.set_lobby_data_as(LOBBY_SETTINGS_KEY, &*settings) {
+        println!("Couldn't publish lobby settings: {}", err);
+    }
+}
+
+fn sync_lobby_settings(
+    client: Res<SteamP2PClient>,
+    mut evs_joined: MessageReader<LobbyJoined>,
+    mut evs_changed: MessageReader<LobbyDataChanged>,
+    mut evs_left: MessageReader<LobbyLeft>,
+    mut settings: ResMut<LobbySettings>,
+) {
+    let joined = evs_joined.read().count() > 0;
+    let changed = evs_changed
+        .read()
+        .any(|ev| ev.member.is_none() && client.get_lobby_id() == Ok(ev.lobby_id));
+    if (joined || changed) && client.is_lobby_owner() == Ok(false) {
+        match client.lobby_data_as::<LobbySettings>(LOBBY_SETTINGS_KEY) {
+            Ok(Some(published)) => *settings = published,
+            Ok(None) => {}
+            Err(err) => println!("Couldn't read lobby settings: {}", err),
+        }
+    }
+    if evs_left.read().count() > 0 {
+        *settings = LobbySettings::default();
+    }
+}
@@ -0,0 +1,45 @@
+use bevy::prelude::*;
+
+use crate::{client::SteamP2PClient, SteamId};
+
+pub struct ModerationPlugin;
+
+impl Plugin for ModerationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<KickNotice>()
+            .add_message::<Kicked>()
+            .add_systems(Update, handle_kick_notices);
+    }
+}
+
+#[derive(Message)]
+pub(crate) struct KickNotice {
+    pub reason: String,
+    pub sender: SteamId,
+}
+
+//We were removed from the lobby by its owner
+#[derive(Message, Clone, Debug)]
+pub struct Kicked {
+    pub reason: String,
+}
+
+fn handle_kick_notices(
+    mut client: ResMut<SteamP2PClient>,
+    mut evs_notice: MessageReader<KickNotice>,
+    mut evs_kicked: MessageWriter<Kicked>,
+) {
+    for ev in evs_notice.read() {
+        if client.get_lobby_owner() != Ok(ev.sender) {
+            println!(
+                "Ignored kick from {:?}, who isn't the lobby owner",
+                ev.sender
+            );
+            continue;
+        }
+        client.leave_lobby();
+        evs_kicked.write(Kicked {
+            reason: ev.reason.clone(),
+        });
+    }
+}
@@ -26,7 +26,7 @@ fn handle_networked_movable(
         let mut vec = Vec3::ZERO;
         if let Some(identity) = network_identity {
             if let Some(ref cli) = client {
-                if identity.id.owner != cli.id {
+                if identity.owner != cli.id {
                     continue;
                 }
             }
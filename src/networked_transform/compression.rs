@@ -0,0 +1,236 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+const POSITION_BITS: u32 = 21;
+const POSITION_MAX: u64 = (1 << POSITION_BITS) - 1;
+const ROTATION_BITS: u32 = 10;
+const ROTATION_MAX: u32 = (1 << ROTATION_BITS) - 1;
+const ROTATION_RANGE: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum TransformEncoding {
+    #[default]
+    Full,
+    Compact(QuantizationBounds),
+}
+
+//Each axis is packed in 21 bits, keep (max - min) / precision under 2097151 steps or the far end gets clamped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuantizationBounds {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub precision: f32,
+}
+
+impl Default for QuantizationBounds {
+    fn default() -> Self {
+        Self {
+            min: Vec3::splat(-1024.),
+            max: Vec3::splat(1024.),
+            precision: 0.001,
+        }
+    }
+}
+
+impl QuantizationBounds {
+    pub fn new(min: Vec3, max: Vec3, precision: f32) -> Self {
+        QuantizationBounds {
+            min,
+            max,
+            precision,
+        }
+    }
+
+    //All three axes in one u64 so a position costs 9 bytes on the wire instead of 16
+    pub fn quantize(&self, position: Vec3) -> u64 {
+        let steps = ((position.clamp(self.min, self.max) - self.min) / self.precision)
+            .round()
+            .min(Vec3::splat(POSITION_MAX as f32));
+        ((steps.x as u64) << (POSITION_BITS * 2))
+            | ((steps.y as u64) << POSITION_BITS)
+            | steps.z as u64
+    }
+
+    //Short fingerprint sent with compact updates, the bounds themselves aren't on the wire
+    pub fn id(&self) -> u16 {
+        let values = [
+            self.min.x,
+            self.min.y,
+            self.min.z,
+            self.max.x,
+            self.max.y,
+            self.max.z,
+            self.precision,
+        ];
+        let mut hash: u32 = 0x811c9dc5;
+        for byte in values
+            .iter()
+            .flat_map(|value| value.to_bits().to_le_bytes())
+        {
+            hash ^= byte as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+        (hash ^ (hash >> 16)) as u16
+    }
+
+    pub fn dequantize(&self, steps: u64) -> Vec3 {
+        let axis = |shift: u32| ((steps >> shift) & POSITION_MAX) as f32;
+        let position = self.min
+            + Vec3::new(axis(POSITION_BITS * 2), axis(POSITION_BITS), axis(0)) * self.precision;
+        position.min(self.max)
+    }
+}
+
+//Smallest-three: drop the largest component, store its index in 2 bits and the others in 10 bits each
+pub fn compress_rotation(rotation: Quat) -> u32 {
+    let mut components = rotation.normalize().to_array();
+    let largest = (0..4)
+        .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+        .unwrap();
+    if components[largest] < 0. {
+        components = components.map(|c| -c);
+    }
+    let mut packed = (largest as u32) << (ROTATION_BITS * 3);
+    let mut shift = ROTATION_BITS * 3;
+    for (index, component) in components.iter().enumerate() {
+        if index == largest {
+            continue;
+        }
+        shift -= ROTATION_BITS;
+        let normalized = ((component + ROTATION_RANGE) / (2. * ROTATION_RANGE)).clamp(0., 1.);
+        packed |= ((normalized * ROTATION_MAX as f32).round() as u32) << shift;
+    }
+    packed
+}
+
+pub fn decompress_rotation(packed: u32) -> Quat {
+    let largest = (packed >> (ROTATION_BITS * 3)) as usize & 0b11;
+    let mut components = [0.; 4];
+    let mut shift = ROTATION_BITS * 3;
+    let mut sum = 0.;
+    for (index, component) in components.iter_mut().enumerate() {
+        if index == largest {
+            continue;
+        }
+        shift -= ROTATION_BITS;
+        let normalized = ((packed >> shift) & ROTATION_MAX) as f32 / ROTATION_MAX as f32;
+        *component = normalized * 2. * ROTATION_RANGE - ROTATION_RANGE;
+        sum += *component * *component;
+    }
+    components[largest] = (1. - sum).max(0.).sqrt();
+    Quat::from_array(components).normalize()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CompactTransform {
+    pub position: Option<u64>,
+    pub rotation: Option<u32>,
+    pub scale: Option<Vec3>,
+    pub bounds_id: u16, //QuantizationBounds::id of the bounds the position was quantized with
+}
+
+impl CompactTransform {
+    pub fn encode(
+        position: Option<Vec3>,
+        rotation: Option<Quat>,
+        scale: Option<Vec3>,
+        bounds: &QuantizationBounds,
+    ) -> Self {
+        CompactTransform {
+            position: position.map(|position| bounds.quantize(position)),
+            rotation: rotation.map(compress_rotation),
+            scale,
+            bounds_id: bounds.id(),
+        }
+    }
+
+    pub fn decode(
+        &self,
+        bounds: &QuantizationBounds,
+    ) -> Result<(Option<Vec3>, Option<Quat>, Option<Vec3>), String> {
+        if self.bounds_id != bounds.id() {
+            return Err("Quantized with different bounds than the receiver's".to_string());
+        }
+        Ok((
+            self.position.map(|steps| bounds.dequantize(steps)),
+            self.rotation.map(decompress_rotation),
+            self.scale,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_round_trip_stays_within_precision() {
+        let bounds = QuantizationBounds::new(Vec3::splat(-100.), Vec3::splat(100.), 0.01);
+        for i in 0..1000 {
+            let t = i as f32 * 0.37;
+            let position = Vec3::new(t.sin() * 99., (t * 1.3).cos() * 50., t % 100. - 50.);
+            let decoded = bounds.dequantize(bounds.quantize(position));
+            let error = (decoded - position).abs().max_element();
+            assert!(
+                error <= bounds.precision * 0.5 + 1e-4,
+                "error {error} at {position}"
+            );
+        }
+    }
+
+    #[test]
+    fn position_outside_bounds_is_clamped() {
+        let bounds = QuantizationBounds::new(Vec3::ZERO, Vec3::splat(10.), 0.1);
+        let decoded = bounds.dequantize(bounds.quantize(Vec3::new(-5., 20., 5.)));
+        assert!((decoded - Vec3::new(0., 10., 5.)).abs().max_element() < 1e-4);
+    }
+
+    #[test]
+    fn rotation_round_trip_stays_within_bounds() {
+        for i in 0..1000 {
+            let t = i as f32 * 0.13;
+            let rotation = Quat::from_euler(EulerRot::YXZ, t, t * 0.7 - 1., (t * 2.1).sin() * 3.);
+            let decoded = decompress_rotation(compress_rotation(rotation));
+            let error = rotation.angle_between(decoded);
+            assert!(error < 0.005, "error {error} for {rotation}");
+        }
+    }
+
+    #[test]
+    fn default_bounds_fit_in_position_bits() {
+        let bounds = QuantizationBounds::default();
+        let steps = ((bounds.max - bounds.min) / bounds.precision).round();
+        assert!(steps.max_element() <= POSITION_MAX as f32);
+        let decoded = bounds.dequantize(bounds.quantize(bounds.max));
+        assert!((decoded - bounds.max).abs().max_element() <= bounds.precision);
+    }
+
+    #[test]
+    fn compact_transform_is_smaller_than_full() {
+        let bounds = QuantizationBounds::default();
+        let position = Vec3::new(12.5, 1., -40.25);
+        let rotation = Quat::from_rotation_y(1.2);
+        let compact = CompactTransform::encode(Some(position), Some(rotation), None, &bounds);
+        let full: (Option<Vec3>, Option<Quat>, Option<Vec3>) =
+            (Some(position), Some(rotation), None);
+        assert!(
+            rmp_serde::to_vec(&compact).unwrap().len() < rmp_serde::to_vec(&full).unwrap().len()
+        );
+        //Positions alone, the quantization has to pay off by itself
+        assert!(
+            rmp_serde::to_vec(&compact.position).unwrap().len()
+                < rmp_serde::to_vec(&Some(position)).unwrap().len()
+        );
+        let (decoded_position, decoded_rotation, _) = compact.decode(&bounds).unwrap();
+        assert!((decoded_position.unwrap() - position).abs().max_element() <= bounds.precision);
+        assert!(decoded_rotation.unwrap().angle_between(rotation) < 0.005);
+    }
+
+    #[test]
+    fn decoding_with_other_bounds_is_rejected() {
+        let bounds = QuantizationBounds::new(Vec3::splat(-10.), Vec3::splat(10.), 0.01);
+        let compact = CompactTransform::encode(Some(Vec3::ONE), None, None, &bounds);
+        assert!(compact.decode(&bounds).is_ok());
+        assert!(compact.decode(&QuantizationBounds::default()).is_err());
+    }
+}
@@ -0,0 +1,455 @@
+use bevy::*;
+use prelude::*;
+use steamworks::networking_types::SendFlags;
+
+use crate::{
+    bandwidth::{NetworkPriority, StateSlot, StateUpdateQueue},
+    client::SteamP2PClient,
+    entity_map::NetworkEntityMap,
+    NetworkData, NetworkId, NetworkIdentity, SteamId,
+};
+
+pub mod compression;
+
+pub use compression::{CompactTransform, QuantizationBounds, TransformEncoding};
+
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
+pub enum TransformSpace {
+    #[default]
+    Local, //Relative to the parent, so children follow it
+    World, //Kept in place in the world whatever the parent does
+}
+
+#[derive(Component)]
+pub struct NetworkedTransform {
+    pub target_position: Vec3,
+    pub target_rotation: Quat,
+    pub target_scale: Vec3,
+    pub sync_position: bool,
+    pub sync_rotation: bool,
+    pub sync_scale: bool,
+    pub send_rate: f32,          //Updates per second, 0 sends on every tick
+    pub position_threshold: f32, //Minimum distance moved before sending
+    pub rotation_threshold: f32, //Minimum angle (radians) rotated before sending
+    pub scale_threshold: f32,    //Minimum scale change before sending
+    pub keyframe_interval: f32,  //Seconds between full resends after movement, 0 disables
+    pub encoding: TransformEncoding,
+    pub space: TransformSpace,
+    received_target: bool,
+    last_sent_position: Vec3,
+    last_sent_rotation: Quat,
+    last_sent_scale: Vec3,
+    send_timer: f32,
+    keyframe_timer: f32,
+    dirty_since_keyframe: bool,
+}
+
+impl Default for NetworkedTransform {
+    fn default() -> Self {
+        Self {
+            target_position: Vec3::ZERO,
+            target_rotation: Quat::default(),
+            target_scale: Vec3::ZERO,
+            sync_position: true,
+            sync_rotation: true,
+            sync_scale: true,
+            send_rate: 30.,
+            position_threshold: 0.001,
+            rotation_threshold: 0.001,
+            scale_threshold: 0.001,
+            keyframe_interval: 1.,
+            encoding: TransformEncoding::Full,
+            space: TransformSpace::Local,
+            received_target: false,
+            last_sent_position: Vec3::ZERO,
+            last_sent_rotation: Quat::default(),
+            last_sent_scale: Vec3::ONE,
+            send_timer: 0.,
+            keyframe_timer: 0.,
+            dirty_since_keyframe: false,
+        }
+    }
+}
+
+impl NetworkedTransform {
+    pub fn new(sync_position: bool, sync_rotation: bool, sync_scale: bool) -> Self {
+        NetworkedTransform {
+            sync_position,
+            sync_rotation,
+            sync_scale,
+            ..default()
+        }
+    }
+
+    pub fn with_send_rate(mut self, send_rate: f32) -> Self {
+        self.send_rate = send_rate;
+        self
+    }
+
+    pub fn with_thresholds(mut self, position: f32, rotation: f32, scale: f32) -> Self {
+        self.position_threshold = position;
+        self.rotation_threshold = rotation;
+        self.scale_threshold = scale;
+        self
+    }
+
+    pub fn with_keyframe_interval(mut self, keyframe_interval: f32) -> Self {
+        self.keyframe_interval = keyframe_interval;
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: TransformEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_space(mut self, space: TransformSpace) -> Self {
+        self.space = space;
+        self
+    }
+
+    pub(crate) fn reset_to(&mut self, transform: &Transform) {
+        self.target_position = transform.translation;
+        self.target_rotation = transform.rotation;
+        self.target_scale = transform.scale;
+        self.received_target = false;
+        self.mark_sent(transform);
+    }
+
+    //World space targets converted to the entity's local space under its current parent
+    fn local_target(
+        &self,
+        current: &GlobalTransform,
+        parent: Option<&GlobalTransform>,
+    ) -> Transform {
+        let mut world = current.compute_transform();
+        if self.sync_position {
+            world.translation = self.target_position;
+        }
+        if self.sync_rotation {
+            world.rotation = self.target_rotation;
+        }
+        if self.sync_scale {
+            world.scale = self.target_scale;
+        }
+        match parent {
+            Some(parent) => GlobalTransform::from(world).reparented_to(parent),
+            None => world,
+        }
+    }
+
+    fn mark_sent(&mut self, transform: &Transform) {
+        self.last_sent_position = transform.translation;
+        self.last_sent_rotation = transform.rotation;
+        self.last_sent_scale = transform.scale;
+    }
+
+    //Returns the fields to send this tick and how, or None if nothing needs to go out
+    fn pending_update(
+        &mut self,
+        transform: &Transform,
+        delta: f32,
+    ) -> Option<((Option<Vec3>, Option<Quat>, Option<Vec3>), SendFlags)> {
+        self.send_timer += delta;
+        self.keyframe_timer += delta;
+        if self.send_rate > 0. {
+            let interval = 1. / self.send_rate;
+            if self.send_timer < interval {
+                return None;
+            }
+            self.send_timer = (self.send_timer - interval).min(interval);
+        }
+
+        if self.dirty_since_keyframe
+            && self.keyframe_interval > 0.
+            && self.keyframe_timer >= self.keyframe_interval
+        {
+            self.keyframe_timer = 0.;
+            self.dirty_since_keyframe = false;
+            self.mark_sent(transform);
+            //The last keyframe may be all a peer gets of where an idle entity settled, it can't be lost
+            return Some((
+                (
+                    self.sync_position.then_some(transform.translation),
+                    self.sync_rotation.then_some(transform.rotation),
+                    self.sync_scale.then_some(transform.scale),
+                ),
+                SendFlags::RELIABLE,
+            ));
+        }
+
+        let position = (self.sync_position
+            && transform.translation.distance(self.last_sent_position) > self.position_threshold)
+            .then_some(transform.translation);
+        let rotation = (self.sync_rotation
+            && transform.rotation.angle_between(self.last_sent_rotation) > self.rotation_threshold)
+            .then_some(transform.rotation);
+        let scale = (self.sync_scale
+            && transform.scale.distance(self.last_sent_scale) > self.scale_threshold)
+            .then_some(transform.scale);
+        if position.is_none() && rotation.is_none() && scale.is_none() {
+            return None;
+        }
+        if !self.dirty_since_keyframe {
+            self.keyframe_timer = 0.;
+        }
+        self.dirty_since_keyframe = true;
+        if let Some(position) = position {
+            self.last_sent_position = position;
+        }
+        if let Some(rotation) = rotation {
+            self.last_sent_rotation = rotation;
+        }
+        if let Some(scale) = scale {
+            self.last_sent_scale = scale;
+        }
+        Some(((position, rotation, scale), SendFlags::UNRELIABLE))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum TransformState {
+    Full(Option<Vec3>, Option<Quat>, Option<Vec3>),
+    Compact(CompactTransform),
+}
+
+#[derive(Message, Debug)]
+pub(crate) struct TransformUpdate {
+    pub network_id: NetworkId,
+    pub state: TransformState,
+    pub sender: SteamId,
+}
+
+pub struct NetworkedTransformPlugin;
+
+impl Plugin for NetworkedTransformPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedUpdate, handle_networked_transform)
+            .add_observer(on_add)
+            .add_observer(on_identity_insert)
+            .add_message::<TransformUpdate>();
+    }
+}
+
+fn handle_networked_transform(
+    client: Res<SteamP2PClient>,
+    mut evs_update: MessageReader<TransformUpdate>,
+    mut networked_transform_query: Query<(
+        &mut Transform,
+        &NetworkIdentity,
+        &mut NetworkedTransform,
+        Option<&NetworkPriority>,
+        &GlobalTransform,
+        Option<&ChildOf>,
+    )>,
+    parent_query: Query<&GlobalTransform>,
+    mut queue: ResMut<StateUpdateQueue>,
+    entity_map: Res<NetworkEntityMap>,
+    time: Res<Time>,
+) {
+    for update in evs_update.read() {
+        let Some(Ok((_, network_identity, mut networked_transform, _, _, _))) = entity_map
+            .get(update.network_id)
+            .map(|entity| networked_transform_query.get_mut(entity))
+        else {
+            continue;
+        };
+        //Late packets from a previous owner would drag it back after a transfer
+        if network_identity.owner != update.sender {
+            continue;
+        }
+        let (position, rotation, scale) = match &update.state {
+            TransformState::Full(position, rotation, scale) => (*position, *rotation, *scale),
+            TransformState::Compact(compact) => {
+                let bounds = match networked_transform.encoding {
+                    TransformEncoding::Compact(bounds) => bounds,
+                    TransformEncoding::Full => QuantizationBounds::default(),
+                };
+                match compact.decode(&bounds) {
+                    Ok(decoded) => decoded,
+                    Err(err) => {
+                        println!(
+                            "Couldn't read transform update of {:?}: {}",
+                            update.network_id, err
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
+        if let Some(position) = position {
+            networked_transform.target_position = position;
+        }
+        if let Some(rotation) = rotation {
+            networked_transform.target_rotation = rotation;
+        }
+        if let Some(scale) = scale {
+            networked_transform.target_scale = scale;
+        }
+        networked_transform.received_target = true;
+    }
+
+    for (
+        mut transform,
+        network_identity,
+        mut networked_transform,
+        priority,
+        global_transform,
+        child_of,
+    ) in networked_transform_query.iter_mut()
+    {
+        if client.id != network_identity.owner {
+            let target = match networked_transform.space {
+                TransformSpace::Local => Transform {
+                    translation: networked_transform.target_position,
+                    rotation: networked_transform.target_rotation,
+                    scale: networked_transform.target_scale,
+                },
+                //Targets start out local, wait for the owner's world transform
+                TransformSpace::World if !networked_transform.received_target => continue,
+                TransformSpace::World => networked_transform.local_target(
+                    global_transform,
+                    child_of.and_then(|child_of| parent_query.get(child_of.parent()).ok()),
+                ),
+            };
+            if networked_transform.sync_position {
+                transform.translation = transform
+                    .translation
+                    .lerp(target.translation, 10. * time.delta_secs());
+            }
+            if networked_transform.sync_rotation {
+                transform.rotation = transform
+                    .rotation
+                    .lerp(target.rotation, 10. * time.delta_secs());
+            }
+            if networked_transform.sync_scale {
+                transform.scale = transform.scale.lerp(target.scale, 10. * time.delta_secs());
+            }
+        } else {
+            let sent = match networked_transform.space {
+                TransformSpace::Local => *transform,
+                TransformSpace::World => global_transform.compute_transform(),
+            };
+            let Some(((position, rotation, scale), flags)) =
+                networked_transform.pending_update(&sent, time.delta_secs())
+            else {
+                continue;
+            };
+            let data = match networked_transform.encoding {
+                TransformEncoding::Full => NetworkData::TransformUpdate(
+                    network_identity.clone(),
+                    position,
+                    rotation,
+                    scale,
+                ),
+                TransformEncoding::Compact(bounds) => NetworkData::CompactTransformUpdate(
+                    network_identity.id,
+                    CompactTransform::encode(position, rotation, scale, &bounds),
+                ),
+            };
+            queue.push(
+                network_identity.id,
+                StateSlot::Transform,
+                data,
+                flags,
+                priority,
+            );
+        }
+    }
+}
+
+fn on_add(
+    trigger: On<Add, NetworkedTransform>,
+    mut transform_query: Query<(&Transform, &mut NetworkedTransform)>,
+) {
+    let Ok((transform, mut networked_transform)) = transform_query.get_mut(trigger.entity) else {
+        return;
+    };
+    networked_transform.reset_to(transform);
+}
+
+//Prefabs get their NetworkIdentity and starting transform after spawning, start from there
+fn on_identity_insert(
+    trigger: On<Insert, NetworkIdentity>,
+    mut transform_query: Query<(&Transform, &mut NetworkedTransform)>,
+) {
+    let Ok((transform, mut networked_transform)) = transform_query.get_mut(trigger.entity) else {
+        return;
+    };
+    networked_transform.reset_to(transform);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn networked_transform(start: &Transform) -> NetworkedTransform {
+        let mut networked_transform = NetworkedTransform::default()
+            .with_send_rate(0.)
+            .with_thresholds(0.1, 0.1, 0.1)
+            .with_keyframe_interval(1.);
+        networked_transform.reset_to(start);
+        networked_transform
+    }
+
+    #[test]
+    fn only_changed_synced_fields_are_sent() {
+        let start = Transform::default();
+        let mut networked_transform = networked_transform(&start);
+        networked_transform.sync_scale = false;
+        let moved = Transform {
+            translation: Vec3::X,
+            rotation: start.rotation,
+            scale: Vec3::splat(2.),
+        };
+        assert_eq!(
+            networked_transform.pending_update(&moved, 0.1),
+            Some(((Some(Vec3::X), None, None), SendFlags::UNRELIABLE))
+        );
+    }
+
+    #[test]
+    fn changes_under_threshold_are_not_sent() {
+        let start = Transform::default();
+        let mut networked_transform = networked_transform(&start);
+        let nudged = Transform::from_translation(Vec3::new(0.05, 0., 0.));
+        assert_eq!(networked_transform.pending_update(&nudged, 0.1), None);
+        //Small moves add up against the last sent position
+        let moved = Transform::from_translation(Vec3::new(0.15, 0., 0.));
+        assert_eq!(
+            networked_transform.pending_update(&moved, 0.1),
+            Some(((Some(moved.translation), None, None), SendFlags::UNRELIABLE))
+        );
+    }
+
+    #[test]
+    fn send_rate_limits_updates() {
+        let start = Transform::default();
+        let mut networked_transform = networked_transform(&start).with_send_rate(10.);
+        let moved = Transform::from_translation(Vec3::X);
+        assert_eq!(networked_transform.pending_update(&moved, 0.05), None);
+        assert!(networked_transform.pending_update(&moved, 0.06).is_some());
+    }
+
+    #[test]
+    fn keyframe_follows_movement_once() {
+        let start = Transform::default();
+        let mut networked_transform = networked_transform(&start);
+        let moved = Transform::from_translation(Vec3::X);
+        assert_eq!(
+            networked_transform.pending_update(&moved, 0.1),
+            Some(((Some(Vec3::X), None, None), SendFlags::UNRELIABLE))
+        );
+        assert_eq!(networked_transform.pending_update(&moved, 0.5), None);
+        //Reliable, so peers that dropped the movement still catch up
+        assert_eq!(
+            networked_transform.pending_update(&moved, 0.6),
+            Some((
+                (Some(Vec3::X), Some(moved.rotation), Some(moved.scale)),
+                SendFlags::RELIABLE
+            ))
+        );
+        //Idle since the keyframe, nothing more to catch up on
+        assert_eq!(networked_transform.pending_update(&moved, 2.), None);
+    }
+}
@@ -0,0 +1,216 @@
+use bevy::prelude::*;
+
+use crate::{
+    client::SteamP2PClient, entity_map::NetworkEntityMap, networked_transform::NetworkedTransform,
+    NetworkId, NetworkIdentity, SteamId,
+};
+
+pub struct OwnershipPlugin;
+
+impl Plugin for OwnershipPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<OwnershipRequestPolicy>()
+            .add_message::<OwnershipRequest>()
+            .add_message::<OwnershipTransfer>()
+            .add_message::<OwnershipRequested>()
+            .add_message::<OwnershipChanged>()
+            .add_systems(
+                Update,
+                (handle_ownership_requests, handle_ownership_transfers),
+            );
+    }
+}
+
+//How the current owner (or the host, if the owner is gone) answers ownership requests
+#[derive(Resource, Default, PartialEq, Clone, Copy, Debug)]
+pub enum OwnershipRequestPolicy {
+    #[default]
+    Grant,
+    Deny,
+    Manual, //Read OwnershipRequested and call transfer_ownership yourself
+}
+
+#[derive(Message)]
+pub(crate) struct OwnershipRequest {
+    pub network_id: NetworkId,
+    pub requester: SteamId,
+}
+
+#[derive(Message)]
+pub(crate) struct OwnershipTransfer {
+    pub network_id: NetworkId,
+    pub new_owner: SteamId,
+    pub sender: SteamId,
+}
+
+#[derive(Message, Clone, Debug)]
+pub struct OwnershipRequested {
+    pub network_identity: NetworkIdentity,
+    pub requester: SteamId,
+}
+
+#[derive(Message, Clone, Debug)]
+pub struct OwnershipChanged {
+    pub network_identity: NetworkIdentity,
+    pub old_owner: SteamId,
+    pub new_owner: SteamId,
+}
+
+//Entities a leaving member borrowed go back to their creator, or to the host if the creator is gone too
+pub(crate) fn return_borrowed(
+    leaver: SteamId,
+    members: &[SteamId],
+    host: SteamId,
+    network_identity: &mut NetworkIdentity,
+) -> bool {
+    if network_identity.owner != leaver || network_identity.id.owner == leaver {
+        return false;
+    }
+    network_identity.owner = if members.contains(&network_identity.id.owner) {
+        network_identity.id.owner
+    } else {
+        host
+    };
+    true
+}
+
+fn handle_ownership_requests(
+    client: Res<SteamP2PClient>,
+    policy: Res<OwnershipRequestPolicy>,
+    mut evs_request: MessageReader<OwnershipRequest>,
+    mut evs_requested: MessageWriter<OwnershipRequested>,
+    entity_map: Res<NetworkEntityMap>,
+    networked_query: Query<&NetworkIdentity>,
+) {
+    for ev in evs_request.read() {
+        let Some(Ok(network_identity)) = entity_map
+            .get(ev.network_id)
+            .map(|entity| networked_query.get(entity))
+        else {
+            continue;
+        };
+        if !client.is_ownership_arbiter(network_identity) {
+            continue;
+        }
+        evs_requested.write(OwnershipRequested {
+            network_identity: network_identity.clone(),
+            requester: ev.requester,
+        });
+        match *policy {
+            OwnershipRequestPolicy::Grant => {
+                if let Err(err) = client.transfer_ownership(network_identity, ev.requester) {
+                    println!("Couldn't grant ownership: {}", err);
+                }
+            }
+            OwnershipRequestPolicy::Deny => {
+                println!(
+                    "Denied ownership of {:?} to {:?}",
+                    network_identity.id, ev.requester
+                );
+            }
+            OwnershipRequestPolicy::Manual => {}
+        }
+    }
+}
+
+fn handle_ownership_transfers(
+    client: Res<SteamP2PClient>,
+    mut evs_transfer: MessageReader<OwnershipTransfer>,
+    mut evs_changed: MessageWriter<OwnershipChanged>,
+    entity_map: Res<NetworkEntityMap>,
+    mut networked_query: Query<(
+        &mut NetworkIdentity,
+        Option<&Transform>,
+        Option<&mut NetworkedTransform>,
+    )>,
+) {
+    let host = client.get_lobby_owner().ok();
+    for ev in evs_transfer.read() {
+        let Some(Ok((mut network_identity, transform, networked_transform))) = entity_map
+            .get(ev.network_id)
+            .map(|entity| networked_query.get_mut(entity))
+        else {
+            continue;
+        };
+        if network_identity.owner != ev.sender && host != Some(ev.sender) {
+            println!(
+                "Rejected ownership transfer of {:?} from {:?}",
+                ev.network_id, ev.sender
+            );
+            continue;
+        }
+        let old_owner = network_identity.owner;
+        if old_owner == ev.new_owner {
+            continue;
+        }
+        network_identity.owner = ev.new_owner;
+        //Both sides restart from the current transform so nobody snaps back to stale targets
+        if let (Some(transform), Some(mut networked_transform)) = (transform, networked_transform) {
+            networked_transform.reset_to(transform);
+        }
+        evs_changed.write(OwnershipChanged {
+            network_identity: network_identity.clone(),
+            old_owner,
+            new_owner: ev.new_owner,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FilePath;
+
+    fn identity(creator: SteamId, owner: SteamId) -> NetworkIdentity {
+        NetworkIdentity {
+            id: NetworkId {
+                owner: creator,
+                index: 0,
+            },
+            parent_id: None,
+            instantiation_path: FilePath::new("Test"),
+            owner,
+        }
+    }
+
+    #[test]
+    fn borrowed_entities_return_to_creator_or_host() {
+        let (host, creator, leaver) = (
+            SteamId::from_raw(1),
+            SteamId::from_raw(2),
+            SteamId::from_raw(3),
+        );
+        let mut borrowed = identity(creator, leaver);
+        assert!(return_borrowed(
+            leaver,
+            &[host, creator],
+            host,
+            &mut borrowed
+        ));
+        assert_eq!(borrowed.owner, creator);
+
+        //The creator left earlier, the host takes it
+        let mut orphaned = identity(creator, leaver);
+        assert!(return_borrowed(leaver, &[host], host, &mut orphaned));
+        assert_eq!(orphaned.owner, host);
+
+        //Created by the leaver, despawned instead
+        let mut created = identity(leaver, leaver);
+        assert!(!return_borrowed(
+            leaver,
+            &[host, creator],
+            host,
+            &mut created
+        ));
+        assert_eq!(created.owner, leaver);
+
+        let mut unrelated = identity(host, creator);
+        assert!(!return_borrowed(
+            leaver,
+            &[host, creator],
+            host,
+            &mut unrelated
+        ));
+        assert_eq!(unrelated.owner, creator);
+    }
+}
@@ -0,0 +1,66 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+
+use crate::{FilePath, InstantiationData};
+
+pub type PrefabSpawner = Box<dyn Fn(&mut Commands, &InstantiationData) -> Entity + Send + Sync>;
+
+pub struct NetworkPrefabPlugin;
+
+impl Plugin for NetworkPrefabPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkPrefabRegister>();
+    }
+}
+
+pub trait NetworkPrefabs {
+    fn register_network_prefab(
+        &mut self,
+        path: &str,
+        spawner: impl Fn(&mut Commands, &InstantiationData) -> Entity + Send + Sync + 'static,
+    ) -> &mut Self;
+}
+
+impl NetworkPrefabs for App {
+    fn register_network_prefab(
+        &mut self,
+        path: &str,
+        spawner: impl Fn(&mut Commands, &InstantiationData) -> Entity + Send + Sync + 'static,
+    ) -> &mut Self {
+        let mut register = self
+            .world_mut()
+            .get_resource_mut::<NetworkPrefabRegister>()
+            .unwrap();
+        register.register(path, spawner);
+        self
+    }
+}
+
+//Spawn functions by instantiation path, the crate adds NetworkIdentity and the starting transform afterwards
+#[derive(Resource, Default)]
+pub struct NetworkPrefabRegister {
+    spawners: HashMap<String, PrefabSpawner>,
+}
+
+impl NetworkPrefabRegister {
+    pub fn register(
+        &mut self,
+        path: &str,
+        spawner: impl Fn(&mut Commands, &InstantiationData) -> Entity + Send + Sync + 'static,
+    ) {
+        if self
+            .spawners
+            .insert(path.to_string(), Box::new(spawner))
+            .is_some()
+        {
+            println!("Network prefab {} was registered twice", path);
+        }
+    }
+
+    pub fn get(&self, path: &FilePath) -> Option<&PrefabSpawner> {
+        self.spawners.get(&path.0)
+    }
+
+    pub fn contains(&self, path: &FilePath) -> bool {
+        self.spawners.contains_key(&path.0)
+    }
+}
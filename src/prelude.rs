@@ -1,9 +1,34 @@
 pub use crate::{
+    bandwidth::{BandwidthBudget, NetworkPriority},
+    chat::LobbyChatReceived,
+    despawn::{DespawnNetworked, NetworkDespawned},
+    early_updates::EarlyUpdateBuffer,
+    entity_map::NetworkEntityMap,
+    host_migration::HostMigrated,
+    invites::{JoinRequestPolicy, JoinRequested, LaunchJoinRequested},
+    lobby::{
+        LobbyComparison, LobbyDataChanged, LobbyDistance, LobbyInfo, LobbyListFilters,
+        LobbyListReceived, LobbyService, LobbyServiceOverride,
+    },
+    lobby_settings::{LobbyPrivacy, LobbySettings},
+    moderation::Kicked,
     networked_messages::{
         message::{Networked, NetworkedMessage},
         register::NetworkedMessages,
     },
-    networked_transform::NetworkedTransform,
-    FilePath, LobbyJoined, NetworkIdentity, OtherJoined, SteamId, SteamP2PClient, SteamP2PPlugin,
+    networked_movable::NetworkedMovable,
+    networked_transform::{
+        NetworkedTransform, QuantizationBounds, TransformEncoding, TransformSpace,
+    },
+    ownership::{OwnershipChanged, OwnershipRequestPolicy, OwnershipRequested},
+    prefab::NetworkPrefabs,
+    relevancy::{NetworkObserver, NetworkVisibility, RelevancyRule, RelevancySettings},
+    replication::Replication,
+    scene::NetworkInstantiationFailed,
+    session::{SessionPolicy, SessionRejectReason, SessionRejected},
+    state::{in_lobby, is_client, is_host, NetworkRole, NetworkState},
+    DuplicateInstantiationPolicy, DuplicateNetworkId, FilePath, InitialSyncComplete,
+    InstantiationQueueSettings, LobbyJoinFailed, LobbyJoined, NetworkIdentity, OrphanPolicy,
+    OrphanedInstantiation, OtherJoined, SteamId, SteamP2PClient, SteamP2PPlugin,
     UnhandledInstantiation,
 };
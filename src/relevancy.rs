@@ -0,0 +1,516 @@
+use bevy::{
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+};
+use steamworks::networking_types::SendFlags;
+
+use crate::{
+    client::SteamP2PClient, entity_map::NetworkEntityMap, replication::ReplicationRegister,
+    scene::PendingSceneInstantiations, InitialSyncComplete, InstantiationData, NetworkData,
+    NetworkId, NetworkIdentity, SteamId,
+};
+
+pub struct RelevancyPlugin;
+
+impl Plugin for RelevancyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RelevancySettings>()
+            .add_message::<RelevancyNotice>()
+            .add_systems(Update, (update_relevancy, handle_relevancy_notices));
+    }
+}
+
+//Decides which peers get each of our entities, from the positions of their NetworkObservers.
+//Every peer should use the same rule, joiners expect a filtered sync from every member
+#[derive(Default, Clone, Copy, Debug)]
+pub enum RelevancyRule {
+    #[default]
+    Everyone,
+    //Within this distance of one of the peer's observers
+    Distance(f32),
+    //Within radius cells of one of the peer's observers
+    Grid {
+        cell_size: f32,
+        radius: i32,
+    },
+    //Only what NetworkVisibility allows
+    Explicit,
+    Custom(fn(position: Vec3, observer: Vec3) -> bool),
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct RelevancySettings {
+    pub rule: RelevancyRule,
+    pub update_interval: f32, //Seconds between relevancy checks
+}
+
+impl Default for RelevancySettings {
+    fn default() -> Self {
+        Self {
+            rule: RelevancyRule::Everyone,
+            update_interval: 0.25,
+        }
+    }
+}
+
+//Put on a networked entity (usually the player or its camera) to see the world from there
+#[derive(Component, Default, Clone, Copy, Debug)]
+pub struct NetworkObserver;
+
+//Per entity override of the RelevancyRule
+#[derive(Component, Default, Clone, Debug)]
+pub enum NetworkVisibility {
+    #[default]
+    Rule,
+    Always,
+    Peers(HashSet<SteamId>),
+}
+
+#[derive(Message)]
+pub(crate) enum RelevancyNotice {
+    Lost {
+        network_id: NetworkId,
+        sender: SteamId,
+    },
+    Observers {
+        positions: Vec<Vec3>,
+        sender: SteamId,
+    },
+    SyncComplete {
+        sender: SteamId,
+    },
+}
+
+//Peers each of our entities currently exists on, kept on the client so every send can use it
+#[derive(Default)]
+pub(crate) struct Relevancy {
+    visible: HashMap<NetworkId, HashSet<SteamId>>,
+    //Observer positions each peer last sent us, their replicas may not be relevant to us
+    observers: HashMap<SteamId, Vec<Vec3>>,
+    //Joiners waiting for InitialSyncComplete until we know what's relevant to them
+    pending_sync: HashSet<SteamId>,
+    //Members whose part of our own initial sync hasn't arrived yet, None when we aren't joining
+    awaiting_sync: Option<HashSet<SteamId>>,
+    filtered: bool,
+    timer: f32,
+}
+
+impl Relevancy {
+    pub fn is_visible(&self, network_id: NetworkId, peer: SteamId) -> bool {
+        match self.visible.get(&network_id) {
+            Some(peers) => peers.contains(&peer),
+            //Not checked yet, nobody has it unless everyone gets everything
+            None => !self.filtered,
+        }
+    }
+
+    //Whether a packet about the entity should go to the peer, entities we don't check may be anywhere
+    pub fn reaches(&self, network_id: NetworkId, peer: SteamId) -> bool {
+        match self.visible.get(&network_id) {
+            Some(peers) => peers.contains(&peer),
+            None => true,
+        }
+    }
+
+    pub fn is_filtered(&self) -> bool {
+        self.filtered
+    }
+
+    pub fn defer_sync(&mut self, peer: SteamId) {
+        self.pending_sync.insert(peer);
+    }
+
+    pub fn await_sync(&mut self, senders: impl IntoIterator<Item = SteamId>) {
+        self.awaiting_sync = Some(senders.into_iter().collect());
+    }
+
+    pub fn is_awaiting_sync(&self) -> bool {
+        self.awaiting_sync.is_some()
+    }
+
+    fn sync_received(&mut self, sender: SteamId) {
+        if let Some(awaiting) = &mut self.awaiting_sync {
+            awaiting.remove(&sender);
+        }
+    }
+
+    //True once every sender still in the lobby is done, members that left are not waited for
+    fn sync_settled(&mut self, members: &[SteamId]) -> bool {
+        let Some(awaiting) = &mut self.awaiting_sync else {
+            return false;
+        };
+        awaiting.retain(|sender| members.contains(sender));
+        if !awaiting.is_empty() {
+            return false;
+        }
+        self.awaiting_sync = None;
+        true
+    }
+}
+
+impl NetworkData {
+    //The entity deciding which peers a packet goes to
+    pub(crate) fn relevant_entity(&self) -> Option<NetworkId> {
+        match self {
+            NetworkData::Destroy(network_identity) => Some(network_identity.id),
+            _ => self.target_entity(),
+        }
+    }
+}
+
+fn is_relevant(
+    rule: &RelevancyRule,
+    visibility: Option<&NetworkVisibility>,
+    position: Option<Vec3>,
+    observers: &[Vec3],
+    peer: SteamId,
+) -> bool {
+    match visibility {
+        Some(NetworkVisibility::Always) => return true,
+        Some(NetworkVisibility::Peers(peers)) => return peers.contains(&peer),
+        Some(NetworkVisibility::Rule) | None => {}
+    }
+    if let RelevancyRule::Explicit = rule {
+        return false;
+    }
+    //Nothing to measure against, e.g. entities without a transform
+    let Some(position) = position else {
+        return true;
+    };
+    match rule {
+        RelevancyRule::Everyone | RelevancyRule::Explicit => true,
+        RelevancyRule::Distance(distance) => observers
+            .iter()
+            .any(|observer| observer.distance(position) <= *distance),
+        RelevancyRule::Grid { cell_size, radius } => {
+            let cell = |point: Vec3| (point / *cell_size).floor().as_ivec3();
+            observers
+                .iter()
+                .any(|observer| (cell(*observer) - cell(position)).abs().max_element() <= *radius)
+        }
+        RelevancyRule::Custom(rule) => observers.iter().any(|observer| rule(position, *observer)),
+    }
+}
+
+//Children are judged by their root so a peer never gets a child without its parent
+fn relevancy_position(
+    entity: Entity,
+    entity_map: &NetworkEntityMap,
+    networked_query: &Query<EntityRef, With<NetworkIdentity>>,
+) -> Option<Vec3> {
+    let mut root = entity;
+    while let Some(parent) = networked_query
+        .get(root)
+        .ok()
+        .and_then(|entity| entity.get::<NetworkIdentity>()?.parent_id)
+        .and_then(|parent_id| entity_map.get(parent_id))
+    {
+        root = parent;
+    }
+    networked_query
+        .get(root)
+        .ok()?
+        .get::<GlobalTransform>()
+        .map(|transform| transform.translation())
+}
+
+fn update_relevancy(
+    mut client: ResMut<SteamP2PClient>,
+    settings: Res<RelevancySettings>,
+    register: Res<ReplicationRegister>,
+    entity_map: Res<NetworkEntityMap>,
+    networked_query: Query<EntityRef, With<NetworkIdentity>>,
+    observer_query: Query<(&NetworkIdentity, &GlobalTransform), With<NetworkObserver>>,
+    time: Res<Time>,
+) {
+    //Taken out so the client can still send while it's updated
+    let mut relevancy = std::mem::take(&mut client.relevancy);
+    relevancy.filtered = !matches!(settings.rule, RelevancyRule::Everyone);
+    relevancy.timer += time.delta_secs();
+    if relevancy.timer >= settings.update_interval {
+        relevancy.timer = 0.;
+        relevancy_pass(
+            &mut relevancy,
+            &client,
+            &settings.rule,
+            &register,
+            &entity_map,
+            &networked_query,
+            &observer_query,
+        );
+    }
+    client.relevancy = relevancy;
+}
+
+fn relevancy_pass(
+    relevancy: &mut Relevancy,
+    client: &SteamP2PClient,
+    rule: &RelevancyRule,
+    register: &ReplicationRegister,
+    entity_map: &NetworkEntityMap,
+    networked_query: &Query<EntityRef, With<NetworkIdentity>>,
+    observer_query: &Query<(&NetworkIdentity, &GlobalTransform), With<NetworkObserver>>,
+) {
+    let Ok(members) = client.get_lobby_members() else {
+        relevancy.visible.clear();
+        relevancy.observers.clear();
+        relevancy.pending_sync.clear();
+        relevancy.awaiting_sync = None;
+        return;
+    };
+    let peers: Vec<SteamId> = members
+        .into_iter()
+        .filter(|member| *member != client.id)
+        .collect();
+    relevancy.observers.retain(|peer, _| peers.contains(peer));
+
+    //Peers decide what we get from where our observers are
+    let own_observers: Vec<Vec3> = observer_query
+        .iter()
+        .filter(|(network_identity, _)| network_identity.owner == client.id)
+        .map(|(_, transform)| transform.translation())
+        .collect();
+    for peer in &peers {
+        let data = NetworkData::ObserverPositions(own_observers.clone());
+        if let Err(err) = client.send_message(&data, *peer, SendFlags::UNRELIABLE) {
+            println!("Couldn't send observer positions: {}", err);
+        }
+    }
+
+    //Despawned or handed over, the new owner decides from now on
+    relevancy.visible.retain(|network_id, _| {
+        entity_map
+            .get(*network_id)
+            .and_then(|entity| networked_query.get(entity).ok())
+            .and_then(|entity| entity.get::<NetworkIdentity>().map(|n| n.owner))
+            == Some(client.id)
+    });
+
+    for entity in networked_query.iter() {
+        let network_identity = entity.get::<NetworkIdentity>().unwrap();
+        if network_identity.owner != client.id {
+            continue;
+        }
+        let fresh = !relevancy.visible.contains_key(&network_identity.id);
+        let handed_off = network_identity.id.owner != client.id;
+        let visible = relevancy
+            .visible
+            .entry(network_identity.id)
+            .or_insert_with(|| {
+                //Our own instantiations only went to ourselves, handed off ones get sent again
+                if relevancy.filtered {
+                    HashSet::new()
+                } else {
+                    peers.iter().copied().collect()
+                }
+            });
+        visible.retain(|peer| peers.contains(peer));
+        if !relevancy.filtered {
+            visible.extend(peers.iter().copied());
+            continue;
+        }
+
+        let position = relevancy_position(entity.id(), entity_map, networked_query);
+        let visibility = entity.get::<NetworkVisibility>();
+        for peer in &peers {
+            let peer_observers = relevancy
+                .observers
+                .get(peer)
+                .map_or(&[][..], |o| o.as_slice());
+            let relevant = is_relevant(rule, visibility, position, peer_observers, *peer);
+            //The previous owner may have left it on peers it isn't relevant to anymore
+            let stale = fresh && handed_off && !relevant;
+            if relevant == visible.contains(peer) && !stale {
+                continue;
+            }
+            let data = if relevant {
+                NetworkData::Instantiate(InstantiationData::from_entity(&entity, register))
+            } else {
+                NetworkData::OutOfRelevance(network_identity.id)
+            };
+            if let Err(err) = client.send_message(&data, *peer, SendFlags::RELIABLE) {
+                println!("Couldn't update relevancy: {}", err);
+                continue;
+            }
+            if relevant {
+                visible.insert(*peer);
+            } else {
+                visible.remove(peer);
+            }
+        }
+    }
+
+    //Everything of ours relevant to a joiner went out above, once we knew where its observers are
+    let synced: Vec<SteamId> = relevancy
+        .pending_sync
+        .iter()
+        .filter(|peer| relevancy.observers.contains_key(*peer))
+        .copied()
+        .collect();
+    for peer in synced {
+        relevancy.pending_sync.remove(&peer);
+        if let Err(err) =
+            client.send_message(&NetworkData::InitialSyncComplete, peer, SendFlags::RELIABLE)
+        {
+            println!("Couldn't send sync complete to joiner: {}", err);
+        }
+    }
+}
+
+fn handle_relevancy_notices(
+    mut client: ResMut<SteamP2PClient>,
+    mut evs_notice: MessageReader<RelevancyNotice>,
+    mut evs_sync_complete: MessageWriter<InitialSyncComplete>,
+    mut pending_scenes: ResMut<PendingSceneInstantiations>,
+    entity_map: Res<NetworkEntityMap>,
+    networked_query: Query<&NetworkIdentity>,
+    mut commands: Commands,
+) {
+    for ev in evs_notice.read() {
+        let (network_id, sender) = match ev {
+            RelevancyNotice::Lost { network_id, sender } => (*network_id, *sender),
+            RelevancyNotice::SyncComplete { sender } => {
+                client.relevancy.sync_received(*sender);
+                continue;
+            }
+            RelevancyNotice::Observers { positions, sender } => {
+                client
+                    .relevancy
+                    .observers
+                    .insert(*sender, positions.clone());
+                continue;
+            }
+        };
+        let lost = |data: &InstantiationData| {
+            data.network_identity.id == network_id && data.network_identity.owner == sender
+        };
+        client
+            .get_instantiation_queue()
+            .retain(|queued| !lost(&queued.data));
+        pending_scenes.0.retain(|(data, _)| !lost(data));
+        let Some(entity) = entity_map.get(network_id) else {
+            continue;
+        };
+        let Ok(network_identity) = networked_query.get(entity) else {
+            continue;
+        };
+        if network_identity.owner != sender {
+            println!(
+                "Ignored relevancy change of {:?} from {:?}, who doesn't own it",
+                network_id, sender
+            );
+            continue;
+        }
+        //Not a destroy, it can come back once it's relevant again
+        commands.entity(entity).try_despawn();
+    }
+
+    if client.relevancy.is_awaiting_sync() {
+        let Ok(members) = client.get_lobby_members() else {
+            return;
+        };
+        if client.relevancy.sync_settled(&members) {
+            println!("Initial sync complete");
+            evs_sync_complete.write(InitialSyncComplete);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SteamId {
+        SteamId::from_raw(1)
+    }
+
+    #[test]
+    fn distance_rule_uses_closest_observer() {
+        let rule = RelevancyRule::Distance(10.);
+        let observers = [Vec3::new(100., 0., 0.), Vec3::new(5., 0., 0.)];
+        assert!(is_relevant(
+            &rule,
+            None,
+            Some(Vec3::ZERO),
+            &observers,
+            peer()
+        ));
+        assert!(!is_relevant(
+            &rule,
+            None,
+            Some(Vec3::new(-20., 0., 0.)),
+            &observers,
+            peer()
+        ));
+        assert!(!is_relevant(&rule, None, Some(Vec3::ZERO), &[], peer()));
+    }
+
+    #[test]
+    fn grid_rule_checks_neighbouring_cells() {
+        let rule = RelevancyRule::Grid {
+            cell_size: 10.,
+            radius: 1,
+        };
+        let observers = [Vec3::new(5., 0., 5.)];
+        assert!(is_relevant(
+            &rule,
+            None,
+            Some(Vec3::new(-5., 0., 15.)),
+            &observers,
+            peer()
+        ));
+        assert!(!is_relevant(
+            &rule,
+            None,
+            Some(Vec3::new(25., 0., 5.)),
+            &observers,
+            peer()
+        ));
+    }
+
+    #[test]
+    fn visibility_overrides_rule() {
+        let rule = RelevancyRule::Distance(1.);
+        let far = Some(Vec3::splat(1000.));
+        let observers = [Vec3::ZERO];
+        let always = NetworkVisibility::Always;
+        let others = NetworkVisibility::Peers(HashSet::from_iter([SteamId::from_raw(2)]));
+        assert!(is_relevant(&rule, Some(&always), far, &observers, peer()));
+        assert!(!is_relevant(
+            &rule,
+            Some(&others),
+            Some(Vec3::ZERO),
+            &observers,
+            peer()
+        ));
+        assert!(!is_relevant(
+            &RelevancyRule::Explicit,
+            None,
+            None,
+            &observers,
+            peer()
+        ));
+    }
+
+    #[test]
+    fn sync_waits_for_every_member_still_in_lobby() {
+        let (a, b, c) = (
+            SteamId::from_raw(1),
+            SteamId::from_raw(2),
+            SteamId::from_raw(3),
+        );
+        let mut relevancy = Relevancy::default();
+        assert!(!relevancy.sync_settled(&[a, b, c]));
+
+        relevancy.await_sync([a, b, c]);
+        relevancy.sync_received(a);
+        assert!(!relevancy.sync_settled(&[a, b, c]));
+        relevancy.sync_received(b);
+        assert!(!relevancy.sync_settled(&[a, b, c]));
+        //c left before sending its part
+        assert!(relevancy.sync_settled(&[a, b]));
+        assert!(!relevancy.is_awaiting_sync());
+        //Only fires once
+        assert!(!relevancy.sync_settled(&[a, b]));
+    }
+}
@@ -0,0 +1,255 @@
+use std::any::TypeId;
+
+use bevy::{platform::collections::HashMap, prelude::*};
+use rmp_serde::from_slice;
+use serde::{de::DeserializeOwned, Serialize};
+use steamworks::networking_types::SendFlags;
+
+use crate::{
+    bandwidth::{NetworkPriority, StateSlot, StateUpdateQueue},
+    client::SteamP2PClient,
+    entity_map::NetworkEntityMap,
+    NetworkData, NetworkId, NetworkIdentity, SteamId,
+};
+
+pub trait ReplicatedComponent: Component + Serialize + DeserializeOwned {}
+impl<T: Component + Serialize + DeserializeOwned> ReplicatedComponent for T {}
+
+pub struct ReplicationPlugin;
+
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ReplicationRegister::new())
+            .add_message::<ComponentReplication>()
+            .add_systems(Update, handle_component_replication);
+    }
+}
+
+pub trait Replication {
+    fn replicate<C: ReplicatedComponent>(&mut self) -> &mut Self;
+}
+
+impl Replication for App {
+    fn replicate<C: ReplicatedComponent>(&mut self) -> &mut Self {
+        self.add_systems(
+            FixedUpdate,
+            (send_replicated_changes::<C>, send_replicated_removals::<C>),
+        );
+        let mut register = self
+            .world_mut()
+            .get_resource_mut::<ReplicationRegister>()
+            .unwrap();
+        register
+            .register::<C>()
+            .expect("Couldn't register replicated component");
+        self
+    }
+}
+
+#[derive(Message)]
+pub(crate) struct ComponentReplication {
+    pub network_id: NetworkId,
+    pub index: u8,
+    pub data: Option<Vec<u8>>, //None when the component was removed
+    pub sender: SteamId,
+}
+
+#[derive(Resource)]
+pub struct ReplicationRegister {
+    pub serializers: Vec<fn(&EntityRef) -> Option<Vec<u8>>>,
+    pub inserters: Vec<fn(&[u8], &mut EntityCommands) -> Result<(), String>>,
+    pub removers: Vec<fn(&mut EntityCommands)>,
+    pub indexes: HashMap<TypeId, u8>,
+    pub counter: u8,
+}
+
+impl ReplicationRegister {
+    pub fn new() -> ReplicationRegister {
+        ReplicationRegister {
+            serializers: Vec::new(),
+            inserters: Vec::new(),
+            removers: Vec::new(),
+            indexes: HashMap::new(),
+            counter: 0,
+        }
+    }
+
+    pub fn register<C: ReplicatedComponent>(&mut self) -> Result<(), String> {
+        if self.indexes.contains_key(&TypeId::of::<C>()) {
+            return Ok(());
+        }
+        //Indexes go over the wire as a u8
+        let Some(next) = self.counter.checked_add(1) else {
+            return Err(format!(
+                "Can't replicate more than {} component types",
+                u8::MAX
+            ));
+        };
+        self.indexes.insert(TypeId::of::<C>(), self.counter);
+        self.counter = next;
+        self.serializers.push(|entity: &EntityRef| {
+            entity
+                .get::<C>()
+                .and_then(|component| rmp_serde::to_vec(component).ok())
+        });
+        self.inserters
+            .push(|buffer: &[u8], entity: &mut EntityCommands| {
+                let component = from_slice::<C>(buffer).map_err(|err| err.to_string())?;
+                entity.insert(component);
+                Ok(())
+            });
+        self.removers.push(|entity: &mut EntityCommands| {
+            entity.remove::<C>();
+        });
+        Ok(())
+    }
+
+    pub fn index_of<C: ReplicatedComponent>(&self) -> Option<u8> {
+        self.indexes.get(&TypeId::of::<C>()).copied()
+    }
+
+    //Serialized state of every replicated component on the entity, by register index
+    pub fn snapshot(&self, entity: &EntityRef) -> Vec<(u8, Vec<u8>)> {
+        self.serializers
+            .iter()
+            .enumerate()
+            .filter_map(|(index, serializer)| Some((index as u8, serializer(entity)?)))
+            .collect()
+    }
+
+    pub fn apply_snapshot(&self, snapshot: &[(u8, Vec<u8>)], entity: &mut EntityCommands) {
+        for (index, data) in snapshot {
+            let Some(inserter) = self.inserters.get(*index as usize) else {
+                continue;
+            };
+            if let Err(err) = inserter(data, entity) {
+                println!("Couldn't read replicated component: {}", err);
+            }
+        }
+    }
+}
+
+impl Default for ReplicationRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn send_replicated_changes<C: ReplicatedComponent>(
+    client: Res<SteamP2PClient>,
+    register: Res<ReplicationRegister>,
+    mut queue: ResMut<StateUpdateQueue>,
+    changed_query: Query<(&NetworkIdentity, &C, Option<&NetworkPriority>), Changed<C>>,
+) {
+    let Some(index) = register.index_of::<C>() else {
+        return;
+    };
+    for (network_identity, component, priority) in changed_query.iter() {
+        if network_identity.owner != client.id {
+            continue;
+        }
+        let Ok(data) = rmp_serde::to_vec(component) else {
+            println!("Couldn't serialize replicated component");
+            continue;
+        };
+        queue.push(
+            network_identity.id,
+            StateSlot::Component(index),
+            NetworkData::ComponentUpdate(network_identity.id, index, data),
+            SendFlags::RELIABLE,
+            priority,
+        );
+    }
+}
+
+fn send_replicated_removals<C: ReplicatedComponent>(
+    client: Res<SteamP2PClient>,
+    register: Res<ReplicationRegister>,
+    mut queue: ResMut<StateUpdateQueue>,
+    mut removed: RemovedComponents<C>,
+    networked_query: Query<(&NetworkIdentity, Option<&NetworkPriority>)>,
+) {
+    let Some(index) = register.index_of::<C>() else {
+        return;
+    };
+    for entity in removed.read() {
+        //Despawned entities are handled by the despawn itself
+        let Ok((network_identity, priority)) = networked_query.get(entity) else {
+            continue;
+        };
+        if network_identity.owner != client.id {
+            continue;
+        }
+        queue.push(
+            network_identity.id,
+            StateSlot::Component(index),
+            NetworkData::ComponentRemoved(network_identity.id, index),
+            SendFlags::RELIABLE,
+            priority,
+        );
+    }
+}
+
+fn handle_component_replication(
+    mut evs_replication: MessageReader<ComponentReplication>,
+    register: Res<ReplicationRegister>,
+    entity_map: Res<NetworkEntityMap>,
+    networked_query: Query<&NetworkIdentity>,
+    mut commands: Commands,
+) {
+    for ev in evs_replication.read() {
+        let Some(entity) = entity_map.get(ev.network_id) else {
+            continue;
+        };
+        let Ok(network_identity) = networked_query.get(entity) else {
+            continue;
+        };
+        if network_identity.owner != ev.sender {
+            println!(
+                "Ignored replicated component from {:?}, who doesn't own {:?}",
+                ev.sender, ev.network_id
+            );
+            continue;
+        }
+        let mut entity_commands = commands.entity(entity);
+        match &ev.data {
+            Some(data) => {
+                let Some(inserter) = register.inserters.get(ev.index as usize) else {
+                    continue;
+                };
+                if let Err(err) = inserter(data, &mut entity_commands) {
+                    println!("Couldn't read replicated component: {}", err);
+                }
+            }
+            None => {
+                if let Some(remover) = register.removers.get(ev.index as usize) {
+                    remover(&mut entity_commands);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Component, Serialize, Deserialize)]
+    struct Health(u32);
+
+    #[test]
+    fn register_rejects_types_past_the_index_range() {
+        let mut register = ReplicationRegister::new();
+        register.counter = u8::MAX - 1;
+        assert!(register.register::<Health>().is_ok());
+        assert_eq!(register.index_of::<Health>(), Some(u8::MAX - 1));
+        assert!(register.register::<Health>().is_ok());
+
+        let mut full = ReplicationRegister::new();
+        full.counter = u8::MAX;
+        assert!(full.register::<Health>().is_err());
+        assert_eq!(full.index_of::<Health>(), None);
+    }
+}
@@ -0,0 +1,96 @@
+use bevy::{
+    asset::LoadState,
+    prelude::*,
+    scene::{DynamicScene, DynamicSceneRoot},
+};
+
+use crate::{
+    despawn::DestroyedIds, entity_map::NetworkEntityMap, replication::ReplicationRegister,
+    InstantiationData, LobbyLeft, NetworkId,
+};
+
+pub struct NetworkScenePlugin;
+
+impl Plugin for NetworkScenePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PendingSceneInstantiations>()
+            .add_message::<NetworkInstantiationFailed>()
+            .add_systems(Update, handle_pending_scenes);
+    }
+}
+
+#[derive(Message, Clone, Debug)]
+pub struct NetworkInstantiationFailed {
+    pub data: InstantiationData,
+    pub error: String,
+}
+
+//Instantiations waiting for their scene asset to finish loading
+#[derive(Resource, Default)]
+pub(crate) struct PendingSceneInstantiations(pub Vec<(InstantiationData, Handle<DynamicScene>)>);
+
+impl PendingSceneInstantiations {
+    pub fn add(&mut self, data: InstantiationData, asset_server: &AssetServer) {
+        let handle =
+            asset_server.load::<DynamicScene>(data.network_identity.instantiation_path.0.clone());
+        self.0.push((data, handle));
+    }
+
+    pub fn contains(&self, network_id: NetworkId) -> bool {
+        self.0
+            .iter()
+            .any(|(data, _)| data.network_identity.id == network_id)
+    }
+}
+
+fn handle_pending_scenes(
+    mut pending: ResMut<PendingSceneInstantiations>,
+    asset_server: Res<AssetServer>,
+    register: Res<ReplicationRegister>,
+    entity_map: Res<NetworkEntityMap>,
+    destroyed: Res<DestroyedIds>,
+    mut evs_left: MessageReader<LobbyLeft>,
+    mut evs_failed: MessageWriter<NetworkInstantiationFailed>,
+    mut commands: Commands,
+) {
+    if evs_left.read().count() > 0 {
+        pending.0.clear();
+        return;
+    }
+    pending.0.retain(|(data, handle)| {
+        //Destroyed while its scene was loading
+        if destroyed.contains(&data.network_identity.id) {
+            return false;
+        }
+        match asset_server.load_state(handle) {
+            LoadState::Loaded => {
+                let mut entity = commands.spawn((
+                    DynamicSceneRoot(handle.clone()),
+                    data.starting_transform,
+                    data.network_identity.clone(),
+                ));
+                register.apply_snapshot(&data.replicated, &mut entity);
+                if let Some(parent) = data
+                    .network_identity
+                    .parent_id
+                    .and_then(|p| entity_map.get(p))
+                {
+                    entity.insert(ChildOf(parent));
+                }
+                false
+            }
+            LoadState::Failed(err) => {
+                println!(
+                    "Couldn't load scene {} for instantiation: {}",
+                    data.network_identity.instantiation_path.0, err
+                );
+                evs_failed.write(NetworkInstantiationFailed {
+                    data: data.clone(),
+                    error: err.to_string(),
+                });
+                false
+            }
+            LoadState::NotLoaded | LoadState::Loading => true,
+        }
+    });
+}
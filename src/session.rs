@@ -0,0 +1,100 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+use bevy::prelude::*;
+use bevy_steamworks::{Client, FriendFlags, LobbyId};
+
+use crate::{client::SteamP2PClient, lobby::LobbyService, SteamId};
+
+pub struct SessionPlugin;
+
+impl Plugin for SessionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SessionPolicy>()
+            .add_message::<SessionRejected>()
+            .add_systems(Update, sync_session_policy);
+    }
+}
+
+//Who may open a P2P session with us, banned members are always turned away
+#[derive(Resource, Default, Clone)]
+pub enum SessionPolicy {
+    #[default]
+    LobbyMembersOnly,
+    Friends,                     //Steam friends, and members of our lobby
+    Allowlist(HashSet<SteamId>), //These ids, and members of our lobby
+    Custom(Arc<dyn Fn(SteamId) -> bool + Send + Sync>),
+    AcceptAll,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SessionRejectReason {
+    Banned,
+    NotLobbyMember,
+    NotFriend,
+    NotAllowed,
+}
+
+#[derive(Message, Clone, Copy, Debug)]
+pub struct SessionRejected {
+    pub remote: SteamId,
+    pub reason: SessionRejectReason,
+}
+
+//What session requests are checked against, shared with Steam's session callback
+#[derive(Default)]
+pub(crate) struct SessionGate {
+    pub policy: SessionPolicy,
+    pub lobby: Option<LobbyId>,
+    pub banned: HashSet<SteamId>,
+}
+
+impl SessionGate {
+    pub fn check(
+        &self,
+        remote: SteamId,
+        lobby_service: &dyn LobbyService,
+        steam_client: &Client,
+    ) -> Result<(), SessionRejectReason> {
+        if self.banned.contains(&remote) {
+            return Err(SessionRejectReason::Banned);
+        }
+        let member = self
+            .lobby
+            .is_some_and(|lobby| lobby_service.lobby_members(lobby).contains(&remote));
+        match &self.policy {
+            SessionPolicy::AcceptAll => Ok(()),
+            _ if member => Ok(()),
+            SessionPolicy::LobbyMembersOnly => Err(SessionRejectReason::NotLobbyMember),
+            SessionPolicy::Friends => {
+                let friend = steam_client
+                    .friends()
+                    .get_friend(remote)
+                    .has_friend(FriendFlags::IMMEDIATE);
+                friend.then_some(()).ok_or(SessionRejectReason::NotFriend)
+            }
+            SessionPolicy::Allowlist(allowed) => allowed
+                .contains(&remote)
+                .then_some(())
+                .ok_or(SessionRejectReason::NotAllowed),
+            SessionPolicy::Custom(accept) => accept(remote)
+                .then_some(())
+                .ok_or(SessionRejectReason::NotAllowed),
+        }
+    }
+}
+
+pub(crate) type SharedSessionGate = Arc<RwLock<SessionGate>>;
+
+fn sync_session_policy(client: Res<SteamP2PClient>, policy: Res<SessionPolicy>) {
+    if !policy.is_changed() {
+        return;
+    }
+    client
+        .session_gate
+        .write()
+        .expect("Session gate poisoned")
+        .policy = policy.clone();
+}
@@ -0,0 +1,246 @@
+use bevy::{ecs::system::SystemParam, prelude::*, state::app::StatesPlugin};
+
+use crate::client::{LobbyStatus, SteamP2PClient};
+
+pub struct NetworkStatePlugin;
+
+impl Plugin for NetworkStatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, update_network_state);
+    }
+
+    //StatesPlugin comes with DefaultPlugins, which is usually added after us, but not with MinimalPlugins
+    fn finish(&self, app: &mut App) {
+        if !app.is_plugin_added::<StatesPlugin>() {
+            app.add_plugins(StatesPlugin);
+        }
+        app.init_state::<NetworkState>()
+            .add_sub_state::<NetworkRole>();
+    }
+}
+
+//Follows the client's LobbyStatus, use OnEnter/OnExit to drive menus
+#[derive(States, Default, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum NetworkState {
+    #[default]
+    Offline,
+    CreatingLobby,
+    Joining,
+    InLobby,
+    Leaving,
+}
+
+//Only exists while InLobby, moves to Host when the lobby is handed to us
+#[derive(SubStates, Default, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[source(NetworkState = NetworkState::InLobby)]
+pub enum NetworkRole {
+    #[default]
+    Client,
+    Host,
+}
+
+pub fn in_lobby() -> impl FnMut(Option<Res<State<NetworkState>>>) -> bool + Clone {
+    in_state(NetworkState::InLobby)
+}
+
+pub fn is_host() -> impl FnMut(Option<Res<State<NetworkRole>>>) -> bool + Clone {
+    in_state(NetworkRole::Host)
+}
+
+pub fn is_client() -> impl FnMut(Option<Res<State<NetworkRole>>>) -> bool + Clone {
+    in_state(NetworkRole::Client)
+}
+
+fn network_state(lobby_status: LobbyStatus) -> NetworkState {
+    match lobby_status {
+        LobbyStatus::OutOfLobby => NetworkState::Offline,
+        LobbyStatus::Creating => NetworkState::CreatingLobby,
+        LobbyStatus::Joining(_) => NetworkState::Joining,
+        LobbyStatus::InLobby(_) => NetworkState::InLobby,
+        LobbyStatus::Leaving => NetworkState::Leaving,
+    }
+}
+
+#[derive(SystemParam)]
+struct NetworkStates<'w> {
+    state: Res<'w, State<NetworkState>>,
+    next_state: ResMut<'w, NextState<NetworkState>>,
+    role: Option<Res<'w, State<NetworkRole>>>,
+    next_role: ResMut<'w, NextState<NetworkRole>>,
+}
+
+impl NetworkStates<'_> {
+    fn follow(&mut self, lobby_status: LobbyStatus, is_owner: bool) {
+        let network_state = network_state(lobby_status);
+        if *self.state.get() != network_state {
+            self.next_state.set(network_state);
+        }
+        if network_state != NetworkState::InLobby {
+            return;
+        }
+        //Set along with InLobby so the host never starts out as a client
+        let network_role = if is_owner {
+            NetworkRole::Host
+        } else {
+            NetworkRole::Client
+        };
+        if self
+            .role
+            .as_ref()
+            .is_none_or(|role| *role.get() != network_role)
+        {
+            self.next_role.set(network_role);
+        }
+    }
+}
+
+fn update_network_state(client: Res<SteamP2PClient>, mut states: NetworkStates) {
+    states.follow(client.lobby_status, client.is_lobby_owner() == Ok(true));
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_steamworks::LobbyId;
+
+    use super::*;
+
+    #[derive(Resource)]
+    struct TestLobby {
+        status: LobbyStatus,
+        is_owner: bool,
+    }
+
+    #[derive(Resource, Default)]
+    struct RunCounts {
+        in_lobby: u32,
+        host: u32,
+        client: u32,
+    }
+
+    fn follow_test_lobby(lobby: Res<TestLobby>, mut states: NetworkStates) {
+        states.follow(lobby.status, lobby.is_owner);
+    }
+
+    fn test_app() -> App {
+        let mut app = App::new();
+        app.add_plugins(StatesPlugin)
+            .init_state::<NetworkState>()
+            .add_sub_state::<NetworkRole>()
+            .init_resource::<RunCounts>()
+            .insert_resource(TestLobby {
+                status: LobbyStatus::OutOfLobby,
+                is_owner: false,
+            })
+            .add_systems(
+                Update,
+                (
+                    follow_test_lobby,
+                    (|mut counts: ResMut<RunCounts>| counts.in_lobby += 1).run_if(in_lobby()),
+                    (|mut counts: ResMut<RunCounts>| counts.host += 1).run_if(is_host()),
+                    (|mut counts: ResMut<RunCounts>| counts.client += 1).run_if(is_client()),
+                ),
+            );
+        app
+    }
+
+    fn set_lobby(app: &mut App, status: LobbyStatus, is_owner: bool) {
+        let mut lobby = app.world_mut().resource_mut::<TestLobby>();
+        lobby.status = status;
+        lobby.is_owner = is_owner;
+        //One frame to request the transition, one for it to apply
+        app.update();
+        app.update();
+    }
+
+    fn state(app: &App) -> NetworkState {
+        *app.world().resource::<State<NetworkState>>().get()
+    }
+
+    fn role(app: &App) -> Option<NetworkRole> {
+        app.world()
+            .get_resource::<State<NetworkRole>>()
+            .map(|role| *role.get())
+    }
+
+    #[test]
+    fn lobby_status_maps_to_network_state() {
+        let lobby = LobbyId::from_raw(1);
+        assert_eq!(
+            network_state(LobbyStatus::OutOfLobby),
+            NetworkState::Offline
+        );
+        assert_eq!(
+            network_state(LobbyStatus::Creating),
+            NetworkState::CreatingLobby
+        );
+        assert_eq!(
+            network_state(LobbyStatus::Joining(lobby)),
+            NetworkState::Joining
+        );
+        assert_eq!(
+            network_state(LobbyStatus::InLobby(lobby)),
+            NetworkState::InLobby
+        );
+        assert_eq!(network_state(LobbyStatus::Leaving), NetworkState::Leaving);
+    }
+
+    #[test]
+    fn role_follows_lobby_ownership() {
+        let lobby = LobbyId::from_raw(1);
+        let mut app = test_app();
+        set_lobby(&mut app, LobbyStatus::Joining(lobby), false);
+        assert_eq!(state(&app), NetworkState::Joining);
+        assert_eq!(role(&app), None);
+
+        set_lobby(&mut app, LobbyStatus::InLobby(lobby), true);
+        assert_eq!(state(&app), NetworkState::InLobby);
+        assert_eq!(role(&app), Some(NetworkRole::Host));
+
+        //Ownership moved to another member
+        set_lobby(&mut app, LobbyStatus::InLobby(lobby), false);
+        assert_eq!(role(&app), Some(NetworkRole::Client));
+
+        //And back to us
+        set_lobby(&mut app, LobbyStatus::InLobby(lobby), true);
+        assert_eq!(role(&app), Some(NetworkRole::Host));
+
+        set_lobby(&mut app, LobbyStatus::Leaving, true);
+        assert_eq!(state(&app), NetworkState::Leaving);
+        assert_eq!(role(&app), None);
+    }
+
+    #[test]
+    fn run_conditions_follow_states() {
+        let lobby = LobbyId::from_raw(1);
+        let mut app = test_app();
+        app.update();
+        assert_eq!(app.world().resource::<RunCounts>().in_lobby, 0);
+
+        set_lobby(&mut app, LobbyStatus::InLobby(lobby), false);
+        app.world_mut().insert_resource(RunCounts::default());
+        app.update();
+        let counts = app.world().resource::<RunCounts>();
+        assert_eq!((counts.in_lobby, counts.host, counts.client), (1, 0, 1));
+
+        set_lobby(&mut app, LobbyStatus::InLobby(lobby), true);
+        app.world_mut().insert_resource(RunCounts::default());
+        app.update();
+        let counts = app.world().resource::<RunCounts>();
+        assert_eq!((counts.in_lobby, counts.host, counts.client), (1, 1, 0));
+
+        set_lobby(&mut app, LobbyStatus::OutOfLobby, false);
+        app.world_mut().insert_resource(RunCounts::default());
+        app.update();
+        let counts = app.world().resource::<RunCounts>();
+        assert_eq!((counts.in_lobby, counts.host, counts.client), (0, 0, 0));
+    }
+
+    #[test]
+    fn finish_adds_states_plugin_when_missing() {
+        let mut app = App::new();
+        app.add_plugins(NetworkStatePlugin);
+        app.finish();
+        assert!(app.is_plugin_added::<StatesPlugin>());
+        assert_eq!(state(&app), NetworkState::Offline);
+    }
+}